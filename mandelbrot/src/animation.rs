@@ -0,0 +1,63 @@
+use num::Complex;
+
+/// 拡大しながら中心を移動するアニメーションの指定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Animation {
+    /// フレーム数
+    pub frames: usize,
+    /// 最後のフレームの中心
+    pub end_center: Complex<f64>,
+    /// 最初のフレームから最後のフレームまでの拡大率
+    pub zoom: f64,
+}
+
+impl Animation {
+    /// 最初のフレームの範囲upper_left lower_rightから、frame番目のフレームの範囲を求める
+    ///
+    /// 拡大率はフレームごとに一定の比率で大きくする
+    /// 中心を直線的に動かすと拡大が進んだ後で目標が画面の外に流れてしまうので、
+    /// 範囲の大きさの縮み具合に比例して中心を目標に近づける
+    pub fn frame_view(&self, upper_left: Complex<f64>, lower_right: Complex<f64>, frame: usize)
+        -> (Complex<f64>, Complex<f64>) {
+        let progress = if self.frames > 1 { frame as f64 / (self.frames - 1) as f64 } else { 0.0 };
+        let scale = self.zoom.powf(-progress);
+
+        let start_center = (upper_left + lower_right) / 2.0;
+        let travel = if self.zoom == 1.0 { progress } else { (1.0 - scale) / (1.0 - 1.0 / self.zoom) };
+        let center = start_center + (self.end_center - start_center) * travel;
+
+        let half = (lower_right - upper_left) / 2.0 * scale;
+        (center - half, center + half)
+    }
+}
+
+/// filenameの拡張子の前に4桁のフレーム番号を入れる
+/// "zoom.png"の3番目のフレームは"zoom0003.png"となる
+pub fn frame_filename(filename: &str, frame: usize) -> String {
+    match filename.rfind('.') {
+        Some(index) if !filename[index..].contains('/') => {
+            format!("{}{:04}{}", &filename[..index], frame, &filename[index..])
+        }
+        _ => format!("{}{:04}", filename, frame),
+    }
+}
+
+#[test]
+fn test_frame_view() {
+    let animation = Animation { frames: 3, end_center: Complex { re: 1.0, im: 1.0 }, zoom: 4.0 };
+    let (upper_left, lower_right) = (Complex { re: -2.0, im: 2.0 }, Complex { re: 2.0, im: -2.0 });
+
+    assert_eq!(animation.frame_view(upper_left, lower_right, 0), (upper_left, lower_right));
+    // 途中のフレームは2倍、最後のフレームは4倍に拡大され、最後に中心がend_centerに来る
+    let (ul, lr) = animation.frame_view(upper_left, lower_right, 1);
+    assert!(((lr.re - ul.re) - 2.0).abs() < 1e-12);
+    let (ul, lr) = animation.frame_view(upper_left, lower_right, 2);
+    assert!((ul - Complex { re: 0.5, im: 1.5 }).norm() < 1e-12);
+    assert!((lr - Complex { re: 1.5, im: 0.5 }).norm() < 1e-12);
+}
+
+#[test]
+fn test_frame_filename() {
+    assert_eq!(frame_filename("zoom.png", 3), "zoom0003.png");
+    assert_eq!(frame_filename("out.d/zoom", 12), "out.d/zoom0012");
+}
@@ -0,0 +1,195 @@
+//! BuddhabrotとNebulabrot
+//!
+//! 通常の描画はピクセルごとにその点cが脱出するまでの回数を数えるが、
+//! Buddhabrotはランダムに選んだcのうち脱出するものについて、軌道z_1, z_2, ...が通ったピクセルを数え、
+//! その密度を明るさにする
+//! 繰り返し上限を3つ与えると、それぞれの上限までに脱出した軌道の密度を赤・緑・青に割り当てる (Nebulabrot)
+
+use num::Complex;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::formula::Formula;
+use crate::interior::in_cardioid_or_bulb;
+use crate::palette::{Palette, Sample};
+use crate::{point_to_pixel, RenderParams, SplitMix64};
+
+/// 1つの種の乱数で選ぶcの数
+/// 種は何番目のまとまりかで決めるので、スレッド数によらず同じ画像になる
+const CHUNK: usize = 1 << 16;
+
+/// Buddhabrotの指定
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buddhabrot {
+    /// 繰り返し上限
+    /// 1つならparams.paletteで色付けし、3つなら順に赤・緑・青の密度とする
+    pub limits: Vec<usize>,
+    /// ランダムに選ぶcの数
+    pub samples: usize,
+}
+
+/// スレッドごとの処理の記録
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OrbitStats {
+    /// 選んだcの数
+    pub samples: usize,
+    /// そのうち上限までに脱出して、軌道を数えたものの数
+    pub escaped: usize,
+    /// 計算にかかった時間
+    pub busy: Duration,
+}
+
+impl Buddhabrot {
+    /// 密度を数えて色に変換し、pixelsに書き込む
+    ///
+    /// cは集合全体を含む-2-2i〜2+2iの正方形から選ぶ (範囲の外のcの軌道も範囲の中を通るため)
+    /// スレッドは手が空くたびにCHUNK個ずつcを取り出し、自分の密度のバッファに数える
+    /// 全てのスレッドが終わったら、バッファを足し合わせる
+    pub fn render(&self,
+                  pixels: &mut [u8],
+                  bounds: (usize, usize),
+                  upper_left: Complex<f64>,
+                  lower_right: Complex<f64>,
+                  params: &RenderParams,
+    ) -> Vec<OrbitStats> {
+        assert!(pixels.len() == bounds.0 * bounds.1 * params.format.channels());
+        let max_limit = self.limits.iter().copied().max().unwrap_or(0);
+
+        // 画面を回転している時は、軌道の点を逆向きに回してから画面の上の位置を求める
+        let center = (upper_left + lower_right) / 2.0;
+        let turn = Complex::from_polar(1.0, -params.rotation);
+        let chunks = Mutex::new((0..self.samples).step_by(CHUNK).enumerate());
+
+        let results: Vec<(Vec<Vec<u32>>, OrbitStats)> = crossbeam::scope(|spawner| {
+            let handles: Vec<_> = (0..params.thread_count()).map(|_| {
+                spawner.spawn(|_| {
+                    let mut density = vec![vec![0u32; bounds.0 * bounds.1]; self.limits.len()];
+                    let mut stats = OrbitStats::default();
+                    let mut orbit = Vec::with_capacity(max_limit);
+                    loop {
+                        let next = chunks.lock().unwrap().next();
+                        let (index, first) = match next {
+                            Some(next) => next,
+                            None => break,
+                        };
+                        let start = Instant::now();
+                        let mut random = SplitMix64(index as u64);
+                        for _ in first..(first + CHUNK).min(self.samples) {
+                            let c = Complex { re: random.next_f64() * 4.0 - 2.0, im: random.next_f64() * 4.0 - 2.0 };
+                            stats.samples += 1;
+                            // カージオイドと周期2の円板の中の点は脱出しない
+                            if params.shortcuts && params.formula == Formula::Mandelbrot && in_cardioid_or_bulb(c) {
+                                continue;
+                            }
+                            let count = match escaping_orbit(c, max_limit, &params.formula, &mut orbit) {
+                                Some(count) => count,
+                                None => continue,
+                            };
+                            stats.escaped += 1;
+                            for (channel, &limit) in density.iter_mut().zip(&self.limits) {
+                                if count >= limit {
+                                    continue;
+                                }
+                                for &z in &orbit {
+                                    let z = if params.rotation == 0.0 { z } else { center + (z - center) * turn };
+                                    if let Some((x, y)) = point_to_pixel(bounds, z, upper_left, lower_right) {
+                                        channel[y * bounds.0 + x] += 1;
+                                    }
+                                }
+                            }
+                        }
+                        stats.busy += start.elapsed();
+                    }
+                    (density, stats)
+                })
+            }).collect();
+
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        }).unwrap();
+
+        let mut density = vec![vec![0u32; bounds.0 * bounds.1]; self.limits.len()];
+        let mut thread_stats = Vec::with_capacity(results.len());
+        for (thread_density, stats) in results {
+            for (total, channel) in density.iter_mut().zip(&thread_density) {
+                for (t, &n) in total.iter_mut().zip(channel) {
+                    *t = t.saturating_add(n);
+                }
+            }
+            thread_stats.push(stats);
+        }
+        self.colorize(pixels, &density, params);
+        thread_stats
+    }
+
+    /// 密度を最大値で割った値の平方根を明るさとする (平方根を取らないと、ごく一部の濃い所以外が暗くなる)
+    /// 1チャンネルではparams.paletteで色に変換する Grayでは通常の描画と逆に、密度の高いほど明るくする
+    /// 3チャンネルではそれぞれを赤・緑・青の明るさとする
+    fn colorize(&self, pixels: &mut [u8], density: &[Vec<u32>], params: &RenderParams) {
+        let channels = params.format.channels();
+        let max: Vec<f64> = density.iter().map(|channel| channel.iter().copied().max().unwrap_or(0).max(1) as f64).collect();
+        for (i, out) in pixels.chunks_mut(channels).enumerate() {
+            let brightness = |k: usize| (density[k][i] as f64 / max[k]).sqrt();
+            if density.len() == 3 {
+                params.format.encode([brightness(0) * 255.0, brightness(1) * 255.0, brightness(2) * 255.0, 255.0], out);
+            } else {
+                let t = if params.palette == Palette::Gray { 1.0 - brightness(0) } else { brightness(0) };
+                params.palette.write_pixel(Some(Sample { value: density[0][i] as f64, t }), params.format, out);
+            }
+        }
+    }
+}
+
+/// cの軌道z_1, z_2, ...をorbitに記録しながら、escape_timeと同じ判定をする
+/// 上限までに脱出すれば脱出までの回数を返す orbitは脱出した点まで含む
+fn escaping_orbit(c: Complex<f64>, limit: usize, formula: &Formula, orbit: &mut Vec<Complex<f64>>) -> Option<usize> {
+    orbit.clear();
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    for i in 0..limit {
+        if z.norm_sqr() > 4.0 {
+            return Some(i);
+        }
+        z = formula.step(z, c);
+        orbit.push(z);
+    }
+    None
+}
+
+#[test]
+fn test_escaping_orbit() {
+    let mut orbit = Vec::new();
+    for c in [Complex { re: 1.0, im: 0.0 }, Complex { re: -0.75, im: 0.2 }, Complex { re: 0.3, im: 0.5 }] {
+        let count = escaping_orbit(c, 255, &Formula::Mandelbrot, &mut orbit);
+        assert_eq!(count, crate::escape_time(c, 255, &Formula::Mandelbrot).map(|e| e.count));
+        if let Some(count) = count {
+            assert_eq!(orbit.len(), count);
+        }
+    }
+    // c = 1の軌道は1, 2, 5で脱出する
+    assert_eq!(escaping_orbit(Complex { re: 1.0, im: 0.0 }, 255, &Formula::Mandelbrot, &mut orbit), Some(3));
+    assert_eq!(orbit, vec![Complex { re: 1.0, im: 0.0 }, Complex { re: 2.0, im: 0.0 }, Complex { re: 5.0, im: 0.0 }]);
+}
+
+#[test]
+fn test_buddhabrot_threads_and_symmetry() {
+    use crate::palette::PixelFormat;
+
+    let bounds = (40, 40);
+    let (upper_left, lower_right) = (Complex { re: -2.0, im: 2.0 }, Complex { re: 2.0, im: -2.0 });
+    let buddhabrot = Buddhabrot { limits: vec![200, 50, 20], samples: 3 * CHUNK + 100 };
+    let render = |threads| {
+        let params = RenderParams { format: PixelFormat::Rgb8, threads, ..RenderParams::default() };
+        let mut pixels = vec![0; bounds.0 * bounds.1 * 3];
+        let stats = buddhabrot.render(&mut pixels, bounds, upper_left, lower_right, &params);
+        assert_eq!(stats.iter().map(|stats| stats.samples).sum::<usize>(), buddhabrot.samples);
+        pixels
+    };
+    // cの選び方はスレッド数によらない
+    let pixels = render(1);
+    assert_eq!(render(3), pixels);
+
+    // 実軸について対称な形になる (標本が有限なのでおおよそ)
+    let row = |y: usize| &pixels[y * bounds.0 * 3..(y + 1) * bounds.0 * 3];
+    let difference: i64 = row(10).iter().zip(row(29)).map(|(&a, &b)| (a as i64 - b as i64).abs()).sum();
+    let brightness: i64 = row(10).iter().map(|&a| a as i64).sum();
+    assert!(brightness > 0 && difference * 5 < brightness, "{} {}", difference, brightness);
+}
@@ -0,0 +1,50 @@
+//! 描画したものを保存するディレクトリの共通処理
+//!
+//! チェックポイントとタイルサーバは、どちらもディレクトリに描画の指定 (キー) を書いたファイルを置き、
+//! 別の描画で保存したファイルを取り違えて使わないようにする
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::error::MandelError;
+
+/// ディレクトリdirを用意し、key_fileに保存されている描画の指定を返す
+/// まだ保存されていなければkeyを書き込んでNoneを返す 一致するかどうかの判断は呼び出し側で行う
+pub(crate) fn claim(dir: &Path, key_file: &str, key: &str) -> Result<Option<String>, MandelError> {
+    fs::create_dir_all(dir).map_err(|e| MandelError::io(dir, e))?;
+    let key_path = dir.join(key_file);
+    match fs::read_to_string(&key_path) {
+        Ok(saved) => Ok(Some(saved)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            fs::write(&key_path, key).map_err(|e| MandelError::io(&key_path, e))?;
+            Ok(None)
+        }
+        Err(e) => Err(MandelError::io(&key_path, e)),
+    }
+}
+
+/// writeで一時ファイルtemporaryに書いてから、pathに名前を変える
+/// 書き込みの途中で止まっても、壊れたファイルがpathに残らない
+pub(crate) fn write_then_rename<F>(temporary: &Path, path: &Path, write: F) -> Result<(), MandelError>
+    where F: FnOnce(&Path) -> Result<(), MandelError>
+{
+    write(temporary)?;
+    fs::rename(temporary, path).map_err(|e| MandelError::io(path, e))
+}
+
+#[test]
+fn test_claim() {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-cache-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(claim(&dir, "key.txt", "first").unwrap(), None);
+    assert_eq!(claim(&dir, "key.txt", "second").unwrap(), Some(String::from("first")));
+
+    let path = dir.join("data.bin");
+    write_then_rename(&dir.join("data.tmp"), &path, |temporary| {
+        fs::write(temporary, b"data").map_err(|e| MandelError::io(temporary, e))
+    }).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    assert!(!dir.join("data.tmp").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
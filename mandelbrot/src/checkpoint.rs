@@ -0,0 +1,224 @@
+//! 途中から再開できるタイル単位の描画
+//!
+//! 巨大な画像や深い拡大は何時間もかかることがあり、途中で落ちると最初からやり直しになる
+//! そこで画像を正方形のタイルに分けて描画し、できたタイルから順にディレクトリに保存する
+//! 再開する時は保存済みのタイルを飛ばし、全てのタイルが揃ったら1枚の画像に組み立てる
+
+use num::Complex;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::cache;
+use crate::error::MandelError;
+use crate::{render_band, RenderParams, ThreadStats};
+
+/// 描画の指定を保存するファイルの名前
+const KEY_FILE: &str = "checkpoint.txt";
+
+/// タイルを保存するディレクトリと再開の指定
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// タイルを保存するディレクトリ
+    pub dir: PathBuf,
+    /// タイルの一辺のピクセル数
+    pub tile_size: usize,
+    /// 描画の指定を表す文字列
+    /// ディレクトリに保存したものと一致しなければ、別の画像のタイルとみなして再開しない
+    pub key: String,
+    /// trueなら保存済みのタイルを使って再開する
+    pub resume: bool,
+}
+
+/// 画像の中のタイルの位置と大きさ (ピクセル単位)
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Tile {
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Checkpoint {
+    /// 画像全体をタイルに分けて描画し、組み立てた結果をpixelsに書き込む
+    ///
+    /// タイルはrender_parallelの行と同じように、スレッドが手が空くたびに次の1枚を取り出して描画する
+    /// 戻り値はスレッドごとの処理の記録と、保存済みで描画を省いたタイルの数
+    pub fn render(&self,
+                  pixels: &mut [u8],
+                  bounds: (usize, usize),
+                  upper_left: Complex<f64>,
+                  lower_right: Complex<f64>,
+                  params: &RenderParams,
+    ) -> Result<(Vec<ThreadStats>, usize), MandelError> {
+        let channels = params.format.channels();
+        assert!(pixels.len() == bounds.0 * bounds.1 * channels);
+        self.prepare()?;
+
+        let tiles = self.tiles(bounds);
+        let (done, pending): (Vec<Tile>, Vec<Tile>) = tiles.iter()
+            .partition(|tile| self.resume && self.is_saved(tile, channels));
+
+        let queue = Mutex::new(pending.into_iter());
+        let results: Vec<Result<ThreadStats, MandelError>> = crossbeam::scope(|spawner| {
+            let handles: Vec<_> = (0..params.thread_count()).map(|_| {
+                spawner.spawn(|_| {
+                    let mut stats = ThreadStats::default();
+                    loop {
+                        let next = queue.lock().unwrap().next();
+                        let tile = match next {
+                            Some(tile) => tile,
+                            None => break,
+                        };
+                        let start = Instant::now();
+                        // 点は画像全体でのピクセルの位置から求めるので、タイルに分けない描画と同じ画像になる
+                        let mut buffer = vec![0; tile.width * tile.height * channels];
+                        let pixels = render_band(&mut buffer, bounds, (tile.left, tile.top), (tile.width, tile.height),
+                                                 upper_left, lower_right, params);
+                        self.save(&tile, &buffer)?;
+                        stats.pixels.add(&pixels);
+                        stats.rows += tile.height;
+                        stats.busy += start.elapsed();
+                    }
+                    Ok(stats)
+                })
+            }).collect();
+
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        }).unwrap();
+        let thread_stats = results.into_iter().collect::<Result<Vec<_>, MandelError>>()?;
+
+        // 全てのタイルが揃ったので、保存したものを読み込んで1枚の画像にする
+        let row_len = bounds.0 * channels;
+        for tile in &tiles {
+            let path = self.tile_path(tile);
+            let buffer = fs::read(&path).map_err(|e| MandelError::io(&path, e))?;
+            let tile_row_len = tile.width * channels;
+            for (y, row) in buffer.chunks(tile_row_len).enumerate() {
+                let start = (tile.top + y) * row_len + tile.left * channels;
+                pixels[start..start + tile_row_len].copy_from_slice(row);
+            }
+        }
+        Ok((thread_stats, done.len()))
+    }
+
+    /// ディレクトリを用意し、描画の指定を書き込む
+    /// 既に別の描画のタイルがある場合や、再開の指定なしに前回のタイルが残っている場合はエラーとする
+    fn prepare(&self) -> Result<(), MandelError> {
+        match cache::claim(&self.dir, KEY_FILE, &self.key)? {
+            Some(_) if !self.resume => Err(self.error("already holds a checkpoint (use --resume to continue it)")),
+            Some(saved) if saved != self.key => {
+                Err(self.error(format!("holds a checkpoint of a different render:\n  {}", saved)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> MandelError {
+        MandelError::Checkpoint { dir: self.dir.display().to_string(), message: message.into() }
+    }
+
+    /// 画像を左上から順にtile_size四方のタイルに分ける 右端と下端のタイルは小さくなる
+    fn tiles(&self, bounds: (usize, usize)) -> Vec<Tile> {
+        let size = self.tile_size.max(1);
+        let mut tiles = Vec::new();
+        for top in (0..bounds.1).step_by(size) {
+            for left in (0..bounds.0).step_by(size) {
+                tiles.push(Tile { left, top, width: size.min(bounds.0 - left), height: size.min(bounds.1 - top) });
+            }
+        }
+        tiles
+    }
+
+    fn tile_path(&self, tile: &Tile) -> PathBuf {
+        self.dir.join(format!("tile_{}_{}.bin", tile.top, tile.left))
+    }
+
+    /// タイルが大きさの合ったファイルとして保存されていればtrue
+    fn is_saved(&self, tile: &Tile, channels: usize) -> bool {
+        fs::metadata(self.tile_path(tile))
+            .is_ok_and(|metadata| metadata.len() == (tile.width * tile.height * channels) as u64)
+    }
+
+    fn save(&self, tile: &Tile, buffer: &[u8]) -> Result<(), MandelError> {
+        let path = self.tile_path(tile);
+        cache::write_then_rename(&path.with_extension("tmp"), &path, |temporary| {
+            fs::write(temporary, buffer).map_err(|e| MandelError::io(temporary, e))
+        })
+    }
+}
+
+/// ディレクトリにあるチェックポイントのファイルを全て消す
+/// 完成した画像を書き出した後の片付けに使う
+pub fn remove(dir: &Path) -> Result<(), MandelError> {
+    let io_error = |e| MandelError::io(dir, e);
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        if name == KEY_FILE || (name.starts_with("tile_") && (name.ends_with(".bin") || name.ends_with(".tmp"))) {
+            fs::remove_file(&path).map_err(|e| MandelError::io(&path, e))?;
+        }
+    }
+    fs::remove_dir(dir).map_err(io_error)
+}
+
+#[test]
+fn test_checkpoint_resume() {
+    use crate::palette::{Palette, PixelFormat};
+
+    let dir = std::env::temp_dir().join(format!("mandelbrot-checkpoint-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let bounds = (50, 30);
+    let (upper_left, lower_right) = (Complex {re: -2.0, im: 1.2}, Complex {re: 1.0, im: -1.2});
+    let params = RenderParams { palette: Palette::fire(), format: PixelFormat::Rgb8, threads: 3, ..RenderParams::default() };
+    let mut checkpoint = Checkpoint { dir: dir.clone(), tile_size: 16, key: String::from("test"), resume: false };
+
+    let mut expected = vec![0; bounds.0 * bounds.1 * 3];
+    crate::render_image(&mut expected, bounds, upper_left, lower_right, &params);
+
+    let mut pixels = vec![0; expected.len()];
+    let (_, skipped) = checkpoint.render(&mut pixels, bounds, upper_left, lower_right, &params).unwrap();
+    assert_eq!(skipped, 0);
+    assert_eq!(pixels, expected);
+
+    // 再開の指定がなければ、残っているタイルを上書きしない
+    let error = checkpoint.render(&mut pixels, bounds, upper_left, lower_right, &params).unwrap_err();
+    assert_eq!(error.exit_code(), 5);
+
+    // 1枚だけ消して再開すると、残りの7枚は描画せずに読み込む
+    fs::remove_file(dir.join("tile_16_32.bin")).unwrap();
+    checkpoint.resume = true;
+    let mut resumed = vec![0; expected.len()];
+    let (_, skipped) = checkpoint.render(&mut resumed, bounds, upper_left, lower_right, &params).unwrap();
+    assert_eq!(skipped, 7);
+    assert_eq!(resumed, expected);
+
+    let other = Checkpoint { key: String::from("other"), ..checkpoint };
+    assert!(other.render(&mut resumed, bounds, upper_left, lower_right, &params).is_err());
+
+    remove(&dir).unwrap();
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_checkpoint_matches_render_image() {
+    use crate::palette::{Palette, PixelFormat};
+
+    // 中心を指定した範囲は端数のある座標になり、タイルごとに角を丸めると結果がずれる
+    // ジッタは点の座標から種を作るので、1ビットでもずれると違う色になる
+    let dir = std::env::temp_dir().join(format!("mandelbrot-checkpoint-jitter-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let bounds = (120, 80);
+    let (upper_left, lower_right) = (Complex {re: -2.0375, im: 1.025}, Complex {re: 1.0375, im: -1.025});
+    let params = RenderParams { palette: Palette::fire(), format: PixelFormat::Rgb8, threads: 3,
+                                samples: 3, jitter: true, ..RenderParams::default() };
+    let checkpoint = Checkpoint { dir: dir.clone(), tile_size: 32, key: String::from("test"), resume: false };
+
+    let mut expected = vec![0; bounds.0 * bounds.1 * 3];
+    crate::render_image(&mut expected, bounds, upper_left, lower_right, &params);
+    let mut pixels = vec![0; expected.len()];
+    checkpoint.render(&mut pixels, bounds, upper_left, lower_right, &params).unwrap();
+    assert!(pixels == expected);
+    remove(&dir).unwrap();
+}
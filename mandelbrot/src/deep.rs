@@ -0,0 +1,285 @@
+//! 摂動論 (perturbation theory) による深い拡大
+//!
+//! Complex<f64>では1ピクセルの幅が1e-13程度より小さくなると、隣り合うピクセルが同じ点になってしまう
+//! そこで画像の中心の1点 (参照点) だけを多倍長の固定小数点数で計算し、
+//! 各ピクセルは参照点の軌道からのずれ (摂動) をf64で計算する
+
+use num::{BigInt, Complex, ToPrimitive, Zero};
+use std::fmt;
+use std::ops::Add;
+use std::str::FromStr;
+
+use crate::Escape;
+
+/// 10進数の文字列を誤差なく保持する数 mantissa * 10^exponent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decimal {
+    mantissa: BigInt,
+    exponent: i64,
+}
+
+impl Decimal {
+    /// 小数点以下の桁数 (指数表記も考慮する)
+    pub fn fraction_digits(&self) -> usize {
+        if self.exponent < 0 { (-self.exponent) as usize } else { 0 }
+    }
+
+    /// 2^bitsを1とする固定小数点数に変換する (切り捨て)
+    pub fn to_fixed(&self, bits: usize) -> BigInt {
+        let ten = BigInt::from(10);
+        if self.exponent >= 0 {
+            (&self.mantissa * num::pow(ten, self.exponent as usize)) << bits
+        } else {
+            (&self.mantissa << bits) / num::pow(ten, (-self.exponent) as usize)
+        }
+    }
+
+    /// 指数をexponent (self.exponent以下) に揃えた時の仮数
+    fn mantissa_at(&self, exponent: i64) -> BigInt {
+        &self.mantissa * num::pow(BigInt::from(10), (self.exponent - exponent) as usize)
+    }
+}
+
+/// "-0.743643887037158704752191506114774" や "1.5e-20" のような10進数をパースする
+impl FromStr for Decimal {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Decimal, ()> {
+        let (number, exponent) = match s.find(['e', 'E']) {
+            Some(index) => (&s[..index], i64::from_str(&s[index + 1..]).map_err(|_| ())?),
+            None => (s, 0),
+        };
+        let (int_part, frac_part) = number.split_once('.').unwrap_or((number, ""));
+        let digits = format!("{}{}", int_part, frac_part);
+        // ".5"や"-.5"のように整数部を省略した書き方は受け付けるが、数字が1つもなければエラーとする
+        if digits.trim_start_matches(['-', '+']).is_empty() || !frac_part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(());
+        }
+        Ok(Decimal {
+            mantissa: BigInt::from_str(&digits).map_err(|_| ())?,
+            exponent: exponent - frac_part.len() as i64,
+        })
+    }
+}
+
+/// "-125e-2" のように仮数と指数で書き出す FromStrでそのまま読み戻せる
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}e{}", self.mantissa, self.exponent)
+    }
+}
+
+/// 指数の小さい方に揃えて足すので、誤差は出ない
+impl Add for &Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: &Decimal) -> Decimal {
+        let exponent = self.exponent.min(other.exponent);
+        Decimal { mantissa: self.mantissa_at(exponent) + other.mantissa_at(exponent), exponent }
+    }
+}
+
+/// 2^bitsを1とする固定小数点数をf64に変換する
+/// BigInt::to_f64だと桁の大きい値がinfになるので、上位64ビットだけを取り出してから指数を掛ける
+pub fn fixed_to_f64(v: &BigInt, bits: usize) -> f64 {
+    let shift = v.bits().saturating_sub(64) as usize;
+    let top = (v >> shift).to_f64().unwrap_or(0.0);
+    let mut exponent = shift as i64 - bits as i64;
+    let mut result = top;
+    // 2^exponentを一度に計算するとアンダーフローするので少しずつ掛ける
+    while exponent < -1000 {
+        result *= 2f64.powi(-1000);
+        exponent += 1000;
+    }
+    while exponent > 1000 {
+        result *= 2f64.powi(1000);
+        exponent -= 1000;
+    }
+    result * 2f64.powi(exponent as i32)
+}
+
+/// 参照点の軌道 Z_0 = 0, Z_{n+1} = Z_n^2 + C
+/// 多倍長で計算し、摂動の計算に使うためf64に丸めて保持する
+#[derive(Debug, Clone)]
+pub struct ReferenceOrbit {
+    orbit: Vec<Complex<f64>>,
+}
+
+impl ReferenceOrbit {
+    /// 参照点center (2^bitsを1とする固定小数点数) の軌道を最大limit回計算する
+    /// 参照点が集合の外にあれば脱出した時点で打ち切る
+    pub fn compute(center: (&BigInt, &BigInt), bits: usize, limit: usize) -> ReferenceOrbit {
+        let four = BigInt::from(4) << (2 * bits);
+        let (mut re, mut im) = (BigInt::zero(), BigInt::zero());
+        let mut orbit = Vec::with_capacity(limit + 1);
+        orbit.push(Complex { re: 0.0, im: 0.0 });
+        for _ in 0..limit {
+            let (re2, im2) = (&re * &re, &im * &im);
+            if &re2 + &im2 > four {
+                break;
+            }
+            let new_im = ((&re * &im) >> (bits - 1)) + center.1;
+            re = ((re2 - im2) >> bits) + center.0;
+            im = new_im;
+            orbit.push(Complex { re: fixed_to_f64(&re, bits), im: fixed_to_f64(&im, bits) });
+        }
+        ReferenceOrbit { orbit }
+    }
+
+    /// 軌道の長さ (参照点が脱出しなければlimit + 1)
+    pub fn len(&self) -> usize {
+        self.orbit.len()
+    }
+
+    /// 軌道は必ず出発点z_0を含むので、常にfalse
+    pub fn is_empty(&self) -> bool {
+        self.orbit.is_empty()
+    }
+
+    /// 参照点からdcだけずれた点c = C + dcについてescape_time_bailoutと同じ判定をする
+    ///
+    /// z_n = Z_n + dz_nとすると dz_{n+1} = 2 Z_n dz_n + dz_n^2 + dc となる
+    /// |z_n| < |dz_n| になると、dzの精度が落ちて結果が崩れる (グリッチ) ので、
+    /// その時点のz_nをdzとして参照軌道の先頭からやり直す (rebasing)
+    /// 参照軌道の終わりに達した場合も同様にやり直す (参照軌道は半径2で打ち切っているので、
+    /// それより大きい半径で判定する時も、やり直せば続けて計算できる)
+    ///
+    /// 戻り値の2つ目はやり直しが起きたかどうか
+    pub fn escape_time(&self, dc: Complex<f64>, limit: usize, bailout_sqr: f64) -> (Option<Escape>, bool) {
+        let mut dz = Complex { re: 0.0, im: 0.0 };
+        let mut m = 0;
+        let mut rebased = false;
+        for i in 0..limit {
+            let z = self.orbit[m] + dz;
+            if z.norm_sqr() > bailout_sqr {
+                return (Some(Escape { count: i, z }), rebased);
+            }
+            if z.norm_sqr() < dz.norm_sqr() || m == self.orbit.len() - 1 {
+                dz = z;
+                m = 0;
+                rebased = true;
+            }
+            dz = (self.orbit[m] * 2.0 + dz) * dz + dc;
+            m += 1;
+        }
+        (None, rebased)
+    }
+}
+
+/// 左上と右下の角で指定された範囲の中心を参照点として軌道を計算する
+/// 戻り値の2つ目と3つ目は、参照点から見た左上と右下の角の位置 (f64で十分表せる)
+pub fn centered_view(upper_left: &(Decimal, Decimal),
+                     lower_right: &(Decimal, Decimal),
+                     limit: usize) -> (ReferenceOrbit, Complex<f64>, Complex<f64>) {
+    let corners = FixedCorners::new(upper_left, lower_right);
+    let orbit = ReferenceOrbit::compute((&corners.center.0, &corners.center.1), corners.bits, limit);
+    let (upper_left, lower_right) = corners.offsets();
+    (orbit, upper_left, lower_right)
+}
+
+/// centered_viewと同じく、範囲の中心から見た左上と右下の角の位置を返す (参照軌道は計算しない)
+/// 角をそのままf64に丸めると幅や高さが0になる深さでも、縦横比を調べられる
+pub fn corner_offsets(upper_left: &(Decimal, Decimal), lower_right: &(Decimal, Decimal)) -> (Complex<f64>, Complex<f64>) {
+    FixedCorners::new(upper_left, lower_right).offsets()
+}
+
+/// 固定小数点数に直した範囲の角と中心
+/// 精度は入力された小数点以下の桁数に64ビットの余裕を加えたものにする
+struct FixedCorners {
+    bits: usize,
+    upper_left: (BigInt, BigInt),
+    lower_right: (BigInt, BigInt),
+    center: (BigInt, BigInt),
+}
+
+impl FixedCorners {
+    fn new(upper_left: &(Decimal, Decimal), lower_right: &(Decimal, Decimal)) -> FixedCorners {
+        let digits = [&upper_left.0, &upper_left.1, &lower_right.0, &lower_right.1]
+            .iter().map(|d| d.fraction_digits()).max().unwrap_or(0);
+        let bits = 64 + (digits as f64 * std::f64::consts::LOG2_10).ceil() as usize;
+
+        let ul = (upper_left.0.to_fixed(bits), upper_left.1.to_fixed(bits));
+        let lr = (lower_right.0.to_fixed(bits), lower_right.1.to_fixed(bits));
+        let center = ((&ul.0 + &lr.0) >> 1, (&ul.1 + &lr.1) >> 1);
+        FixedCorners { bits, upper_left: ul, lower_right: lr, center }
+    }
+
+    fn offsets(&self) -> (Complex<f64>, Complex<f64>) {
+        let delta = |p: &(BigInt, BigInt)| Complex {
+            re: fixed_to_f64(&(&p.0 - &self.center.0), self.bits),
+            im: fixed_to_f64(&(&p.1 - &self.center.1), self.bits),
+        };
+        (delta(&self.upper_left), delta(&self.lower_right))
+    }
+}
+
+/// 中心centerと範囲の幅widthで指定された場合の参照点の軌道を計算する
+/// 参照点はcenterそのものなので、各ピクセルのずれは中心を原点とした範囲 (Viewport::centered) で求まる
+///
+/// 精度はcenterの小数点以下の桁数と、幅を表すのに必要な桁数の大きい方に64ビットの余裕を加えたものにする
+pub fn centered_at(center: &(Decimal, Decimal), width: f64, limit: usize) -> ReferenceOrbit {
+    let digits = center.0.fraction_digits()
+        .max(center.1.fraction_digits())
+        .max((-width.log10()).ceil().max(0.0) as usize);
+    let bits = 64 + (digits as f64 * std::f64::consts::LOG2_10).ceil() as usize;
+    ReferenceOrbit::compute((&center.0.to_fixed(bits), &center.1.to_fixed(bits)), bits, limit)
+}
+
+#[test]
+fn test_parse_decimal() {
+    let d: Decimal = "-1.25".parse().unwrap();
+    assert_eq!(d, Decimal { mantissa: BigInt::from(-125), exponent: -2 });
+    assert_eq!(d.fraction_digits(), 2);
+    assert_eq!("15e-3".parse(), Ok(Decimal { mantissa: BigInt::from(15), exponent: -3 }));
+    assert_eq!(".5".parse(), Ok(Decimal { mantissa: BigInt::from(5), exponent: -1 }));
+    assert!("".parse::<Decimal>().is_err());
+    assert!("-".parse::<Decimal>().is_err());
+    assert!("1.x".parse::<Decimal>().is_err());
+    assert!(" 1.0".parse::<Decimal>().is_err());
+}
+
+#[test]
+fn test_decimal_display_and_add() {
+    let a: Decimal = "-0.743643887037158704752191506114774".parse().unwrap();
+    let b: Decimal = "1.5e-20".parse().unwrap();
+    assert_eq!(a.to_string().parse(), Ok(a.clone()));
+    assert_eq!(&a + &b, "-0.743643887037158704737191506114774".parse().unwrap());
+    assert_eq!((&b + &b).to_string(), "30e-21");
+}
+
+#[test]
+fn test_fixed_to_f64() {
+    let d: Decimal = "-1.25".parse().unwrap();
+    assert_eq!(fixed_to_f64(&d.to_fixed(200), 200), -1.25);
+    let tiny: Decimal = "3e-250".parse().unwrap();
+    let v = fixed_to_f64(&tiny.to_fixed(900), 900);
+    assert!((v / 3e-250 - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_perturbation_matches_escape_time() {
+    use crate::formula::Formula;
+
+    let bits = 128;
+    let center: (Decimal, Decimal) = ("-0.75".parse().unwrap(), "0.1".parse().unwrap());
+    let orbit = ReferenceOrbit::compute((&center.0.to_fixed(bits), &center.1.to_fixed(bits)), bits, 1000);
+    for &dc in &[Complex { re: 0.0, im: 0.0 }, Complex { re: 0.01, im: -0.02 }, Complex { re: -0.3, im: 0.05 }] {
+        let c = Complex { re: -0.75, im: 0.1 } + dc;
+        for bailout_sqr in [crate::BAILOUT_SQR, crate::SMOOTH_BAILOUT_SQR] {
+            let zero = Complex { re: 0.0, im: 0.0 };
+            let expected = crate::escape_time_bailout(zero, c, 1000, &Formula::Mandelbrot, bailout_sqr).map(|e| e.count);
+            assert_eq!(orbit.escape_time(dc, 1000, bailout_sqr).0.map(|e| e.count), expected);
+        }
+    }
+}
+
+#[test]
+fn test_corner_offsets() {
+    let decimal = |re: &str, im: &str| (re.parse().unwrap(), im.parse().unwrap());
+    // f64に丸めると幅も高さも0になる範囲
+    let upper_left = decimal("-0.50000000000000000000003", "0.00000000000000000000002");
+    let lower_right = decimal("-0.49999999999999999999997", "-0.00000000000000000000002");
+    let (ul, lr) = corner_offsets(&upper_left, &lower_right);
+    assert!((ul.re / -3e-23 - 1.0).abs() < 1e-9 && (ul.im / 2e-23 - 1.0).abs() < 1e-9);
+    assert!((lr.re / 3e-23 - 1.0).abs() < 1e-9 && (lr.im / -2e-23 - 1.0).abs() < 1e-9);
+}
@@ -0,0 +1,51 @@
+//! 距離推定 (distance estimation)
+//!
+//! zと一緒に微分dz/dc (ジュリア集合ではdz/dz0) を計算し、脱出した点から集合の境界までの距離を見積もる
+//! ピクセルより細い糸状の部分も、距離がピクセルの幅より小さいかどうかで描けるようになる
+
+use num::Complex;
+
+use crate::formula::Formula;
+
+/// 距離の見積もりには|z|が十分大きくなるまで繰り返す必要があるので、脱出の半径を大きく取る
+const BAILOUT_SQR: f64 = 1e6;
+
+/// zを初期値としてformulaの漸化式を繰り返し、脱出した点から集合の境界までの推定距離を返す
+/// juliaがtrueならcを固定したジュリア集合として、初期値zについて微分する
+/// 繰り返し上限までに脱出しなければNone
+/// formulaが複素微分できない場合 (Formula::derivativeがNone) は距離を見積もれないので、常にNone
+pub fn distance_estimate(mut z: Complex<f64>, c: Complex<f64>, limit: usize, formula: &Formula, julia: bool)
+    -> Option<f64> {
+    let (mut dz, constant) = if julia {
+        (Complex { re: 1.0, im: 0.0 }, Complex { re: 0.0, im: 0.0 })
+    } else {
+        (Complex { re: 0.0, im: 0.0 }, Complex { re: 1.0, im: 0.0 })
+    };
+    for _ in 0..limit {
+        let norm_sqr = z.norm_sqr();
+        if norm_sqr > BAILOUT_SQR {
+            let norm = norm_sqr.sqrt();
+            return Some(norm * norm.ln() / dz.norm());
+        }
+        dz = formula.derivative(z)? * dz + constant;
+        z = formula.step(z, c);
+    }
+    None
+}
+
+#[test]
+fn test_distance_estimate() {
+    let zero = Complex { re: 0.0, im: 0.0 };
+    assert_eq!(distance_estimate(zero, zero, 1000, &Formula::Mandelbrot, false), None);
+
+    // 実軸上の集合の右端は0.25なので、c = 0.5から境界までの距離は0.25程度
+    let d = distance_estimate(zero, Complex { re: 0.5, im: 0.0 }, 1000, &Formula::Mandelbrot, false).unwrap();
+    assert!(d > 0.05 && d < 0.5, "{}", d);
+
+    // c = 0のジュリア集合は単位円なので、z = 2からの距離は1程度
+    let d = distance_estimate(Complex { re: 2.0, im: 0.0 }, zero, 1000, &Formula::Mandelbrot, true).unwrap();
+    assert!(d > 0.5 && d < 2.0, "{}", d);
+
+    // 複素微分できない漸化式では見積もらない
+    assert_eq!(distance_estimate(zero, Complex { re: 0.5, im: 0.0 }, 1000, &Formula::BurningShip, false), None);
+}
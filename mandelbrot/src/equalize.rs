@@ -0,0 +1,86 @@
+//! ヒストグラム平坦化による色付け
+//!
+//! 繰り返し回数をそのまま上限で割ると、ほとんどのピクセルが少ない回数に集中し、色の範囲の大半が使われない
+//! そこで画像全体の回数のヒストグラムを取り、累積分布で0.0〜1.0に写すことで、色が均等に使われるようにする
+
+/// 繰り返し回数のヒストグラム
+/// 回数を整数に切り捨てたものを区間とする
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bins: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    /// 0からlimitまでの回数を数えるヒストグラムを作る
+    pub fn new(limit: usize) -> Histogram {
+        Histogram { bins: vec![0; limit + 1], total: 0 }
+    }
+
+    pub fn add(&mut self, count: f64) {
+        let bin = self.bin(count);
+        self.bins[bin] += 1;
+        self.total += 1;
+    }
+
+    /// 別のスレッドで数えたヒストグラムを足し合わせる
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bin, &n) in self.bins.iter_mut().zip(&other.bins) {
+            *bin += n;
+        }
+        self.total += other.total;
+    }
+
+    /// 累積分布を求める
+    pub fn equalizer(&self) -> Equalizer {
+        let mut below = Vec::with_capacity(self.bins.len());
+        let mut sum = 0;
+        for &n in &self.bins {
+            below.push(sum as f64 / self.total.max(1) as f64);
+            sum += n;
+        }
+        Equalizer { below, histogram: self.clone() }
+    }
+
+    fn bin(&self, count: f64) -> usize {
+        (count.max(0.0) as usize).min(self.bins.len() - 1)
+    }
+}
+
+/// ヒストグラムの累積分布で回数を0.0〜1.0に写す
+#[derive(Debug, Clone)]
+pub struct Equalizer {
+    /// below[i]は回数がi未満のピクセルの割合
+    below: Vec<f64>,
+    histogram: Histogram,
+}
+
+impl Equalizer {
+    /// countより少ない回数のピクセルの割合を返す
+    /// 連続的な回数 (--smooth) の小数部分は区間の中で線形に補間する
+    pub fn map(&self, count: f64) -> f64 {
+        let bin = self.histogram.bin(count);
+        let fraction = (count - bin as f64).clamp(0.0, 1.0);
+        let width = self.histogram.bins[bin] as f64 / self.histogram.total.max(1) as f64;
+        self.below[bin] + fraction * width
+    }
+}
+
+#[test]
+fn test_equalizer() {
+    let mut histogram = Histogram::new(10);
+    for count in [1.0, 1.0, 1.0, 2.0] {
+        histogram.add(count);
+    }
+    let mut other = Histogram::new(10);
+    other.add(9.0);
+    histogram.merge(&other);
+
+    let equalizer = histogram.equalizer();
+    assert_eq!(equalizer.map(1.0), 0.0);
+    assert_eq!(equalizer.map(1.5), 0.3);
+    assert_eq!(equalizer.map(2.0), 0.6);
+    // 回数の差は大きくても、2と9の間には他のピクセルがないので隣り合う色になる
+    assert_eq!(equalizer.map(9.0), 0.8);
+    assert_eq!(equalizer.map(20.0), 1.0);
+}
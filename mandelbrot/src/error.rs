@@ -0,0 +1,79 @@
+//! 描画の各段階で起きるエラー
+//!
+//! どの段階で何が起きたかをメッセージにし、コマンドラインでは種類ごとに別の終了コードを返す
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum MandelError {
+    /// コマンドライン引数の誤り
+    /// argumentは問題のあった引数 (オプション名や位置引数の名前)
+    Argument { argument: String, message: String },
+    /// ファイルの読み書きの失敗
+    Io { path: String, source: io::Error },
+    /// 画像のエンコードやデコードの失敗
+    Encoding { path: String, message: String },
+    /// チェックポイントのディレクトリが今回の描画に使えない
+    Checkpoint { dir: String, message: String },
+    /// タイルサーバのディレクトリに別の描画のタイルが保存されている
+    TileCache { dir: String, message: String },
+}
+
+impl MandelError {
+    pub fn argument(argument: &str, message: impl Into<String>) -> MandelError {
+        MandelError::Argument { argument: argument.to_string(), message: message.into() }
+    }
+
+    pub fn io(path: impl AsRef<std::path::Path>, source: io::Error) -> MandelError {
+        MandelError::Io { path: path.as_ref().display().to_string(), source }
+    }
+
+    pub fn encoding(path: &str, message: impl fmt::Display) -> MandelError {
+        MandelError::Encoding { path: path.to_string(), message: message.to_string() }
+    }
+
+    /// プロセスの終了コード
+    /// 0と、panicした時の101とは重ならないようにする
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MandelError::Argument { .. } => 2,
+            MandelError::Io { .. } => 3,
+            MandelError::Encoding { .. } => 4,
+            MandelError::Checkpoint { .. } => 5,
+            MandelError::TileCache { .. } => 6,
+        }
+    }
+}
+
+impl fmt::Display for MandelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MandelError::Argument { argument, message } => write!(f, "invalid argument {}: {}", argument, message),
+            MandelError::Io { path, source } => write!(f, "{}: {}", path, source),
+            MandelError::Encoding { path, message } => write!(f, "{}: {}", path, message),
+            MandelError::Checkpoint { dir, message } => write!(f, "checkpoint {}: {}", dir, message),
+            MandelError::TileCache { dir, message } => write!(f, "tile cache {}: {}", dir, message),
+        }
+    }
+}
+
+impl std::error::Error for MandelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MandelError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_error_message_and_exit_code() {
+    let error = MandelError::argument("--max-iter", "expected a number, got \"lots\"");
+    assert_eq!(error.to_string(), "invalid argument --max-iter: expected a number, got \"lots\"");
+    assert_eq!(error.exit_code(), 2);
+
+    let error = MandelError::io("out/mandel.png", io::Error::new(io::ErrorKind::NotFound, "No such file or directory"));
+    assert_eq!(error.to_string(), "out/mandel.png: No such file or directory");
+    assert_eq!(error.exit_code(), 3);
+}
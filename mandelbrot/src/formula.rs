@@ -0,0 +1,117 @@
+use num::Complex;
+use std::fmt;
+use std::str::FromStr;
+
+/// 繰り返し計算する漸化式 z -> f(z) + c
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Formula {
+    /// z = z^2 + c
+    Mandelbrot,
+    /// 実部と虚部の絶対値を取ってから2乗する z = (|re| + |im|i)^2 + c
+    BurningShip,
+    /// 共役複素数を2乗する z = conj(z)^2 + c
+    Tricorn,
+    /// 整数乗 z = z^n + c
+    Multibrot(u32),
+    /// 実数乗 z = z^p + c
+    MultibrotReal(f64),
+}
+
+impl Formula {
+    /// zを1回更新した値を返す
+    pub fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        match *self {
+            Formula::Mandelbrot => z * z + c,
+            Formula::BurningShip => {
+                let z = Complex { re: z.re.abs(), im: z.im.abs() };
+                z * z + c
+            }
+            Formula::Tricorn => {
+                let z = z.conj();
+                z * z + c
+            }
+            Formula::Multibrot(n) => z.powu(n) + c,
+            Formula::MultibrotReal(p) => z.powf(p) + c,
+        }
+    }
+
+    /// zについての微分 f'(z) を返す (+ cの部分は含まない)
+    /// BurningShipとTricornは複素微分できないのでNone
+    pub fn derivative(&self, z: Complex<f64>) -> Option<Complex<f64>> {
+        match *self {
+            Formula::Mandelbrot => Some(z * 2.0),
+            Formula::BurningShip | Formula::Tricorn => None,
+            Formula::Multibrot(n) => Some(z.powu(n - 1) * n as f64),
+            Formula::MultibrotReal(p) => Some(z.powf(p - 1.0) * p),
+        }
+    }
+
+    /// 漸化式の次数 連続的な繰り返し回数を求めるのに使う
+    pub fn degree(&self) -> f64 {
+        match *self {
+            Formula::Mandelbrot | Formula::BurningShip | Formula::Tricorn => 2.0,
+            Formula::Multibrot(n) => n as f64,
+            Formula::MultibrotReal(p) => p,
+        }
+    }
+}
+
+/// "mandelbrot", "burning-ship", "tricorn", "multibrot:3", "multibrot:2.5" をパースする
+impl FromStr for Formula {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Formula, ()> {
+        match s {
+            "mandelbrot" => return Ok(Formula::Mandelbrot),
+            "burning-ship" => return Ok(Formula::BurningShip),
+            "tricorn" => return Ok(Formula::Tricorn),
+            _ => {}
+        }
+        let exponent = s.strip_prefix("multibrot:").ok_or(())?;
+        // 指数が2以上の整数ならpowuで計算した方が速く正確
+        if let Ok(n) = u32::from_str(exponent) {
+            if n >= 2 {
+                return Ok(Formula::Multibrot(n));
+            }
+        }
+        match f64::from_str(exponent) {
+            Ok(p) if p > 1.0 => Ok(Formula::MultibrotReal(p)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// FromStrと同じ形式で書き出す
+impl fmt::Display for Formula {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Formula::Mandelbrot => write!(f, "mandelbrot"),
+            Formula::BurningShip => write!(f, "burning-ship"),
+            Formula::Tricorn => write!(f, "tricorn"),
+            Formula::Multibrot(n) => write!(f, "multibrot:{}", n),
+            Formula::MultibrotReal(p) => write!(f, "multibrot:{}", p),
+        }
+    }
+}
+
+#[test]
+fn test_parse_formula() {
+    assert_eq!("tricorn".parse(), Ok(Formula::Tricorn));
+    assert_eq!("multibrot:3".parse(), Ok(Formula::Multibrot(3)));
+    assert_eq!("multibrot:2.5".parse(), Ok(Formula::MultibrotReal(2.5)));
+    assert_eq!("multibrot:1".parse::<Formula>(), Err(()));
+    assert_eq!("julia".parse::<Formula>(), Err(()));
+    for formula in [Formula::BurningShip, Formula::Multibrot(4), Formula::MultibrotReal(2.5)] {
+        assert_eq!(formula.to_string().parse(), Ok(formula));
+    }
+}
+
+#[test]
+fn test_formula_step() {
+    let z = Complex { re: -1.0, im: 2.0 };
+    let c = Complex { re: 0.5, im: 0.0 };
+    assert_eq!(Formula::Mandelbrot.step(z, c), Complex { re: -2.5, im: -4.0 });
+    assert_eq!(Formula::BurningShip.step(z, c), Complex { re: -2.5, im: 4.0 });
+    assert_eq!(Formula::Tricorn.step(z, c), Complex { re: -2.5, im: 4.0 });
+    assert_eq!(Formula::Multibrot(2).step(z, c), Formula::Mandelbrot.step(z, c));
+}
@@ -0,0 +1,71 @@
+//! 集合の内部の点を早く見分けるための近道
+//!
+//! 集合の内部の点は繰り返し上限まで計算しないと判定できないので、上限を大きくすると描画時間の大半を占める
+
+use num::Complex;
+
+use crate::formula::Formula;
+use crate::Escape;
+
+/// cがマンデルブロ集合の主カージオイド、または周期2の円板の中にあればtrueを返す
+/// どちらも式で判定でき、これらの点は繰り返し計算するまでもなく集合に含まれる
+pub fn in_cardioid_or_bulb(c: Complex<f64>) -> bool {
+    let x = c.re - 0.25;
+    let y2 = c.im * c.im;
+    let q = x * x + y2;
+    if q * (q + x) <= 0.25 * y2 {
+        return true;
+    }
+    (c.re + 1.0) * (c.re + 1.0) + y2 <= 0.0625
+}
+
+/// escape_time_bailoutと同じ判定をするが、zが周期的な軌道に入ったことを検出したら上限を待たずにNoneを返す
+///
+/// Brentの方法で、2のべき乗回ごとに保存したzと現在のzを比べる
+/// 戻り値の2つ目は周期を検出して打ち切ったかどうか
+pub fn escape_time_periodic(mut z: Complex<f64>, c: Complex<f64>, limit: usize, formula: &Formula, bailout_sqr: f64)
+    -> (Option<Escape>, bool) {
+    let mut saved = z;
+    let mut period = 1;
+    let mut steps = 0;
+    for i in 0..limit {
+        if z.norm_sqr() > bailout_sqr {
+            return (Some(Escape { count: i, z }), false);
+        }
+        z = formula.step(z, c);
+
+        if (z - saved).norm_sqr() < 1e-24 {
+            return (None, true);
+        }
+        steps += 1;
+        if steps == period {
+            saved = z;
+            steps = 0;
+            period *= 2;
+        }
+    }
+    (None, false)
+}
+
+#[test]
+fn test_in_cardioid_or_bulb() {
+    assert!(in_cardioid_or_bulb(Complex { re: 0.0, im: 0.0 }));
+    assert!(in_cardioid_or_bulb(Complex { re: -1.0, im: 0.1 }));
+    assert!(in_cardioid_or_bulb(Complex { re: 0.2, im: 0.5 }));
+    assert!(!in_cardioid_or_bulb(Complex { re: 0.3, im: 0.0 }));
+    // 周期3の円板は集合に含まれるが、この判定では見つけられない
+    assert!(!in_cardioid_or_bulb(Complex { re: -0.12, im: 0.75 }));
+}
+
+#[test]
+fn test_escape_time_periodic() {
+    let formula = Formula::Mandelbrot;
+    let zero = Complex { re: 0.0, im: 0.0 };
+    // 周期3の円板の中心付近
+    assert_eq!(escape_time_periodic(zero, Complex { re: -0.12, im: 0.75 }, 100000, &formula, crate::BAILOUT_SQR), (None, true));
+
+    let c = Complex { re: -0.75, im: 0.1 };
+    let (escape, periodic) = escape_time_periodic(zero, c, 1000, &formula, crate::BAILOUT_SQR);
+    assert!(!periodic);
+    assert_eq!(escape, crate::escape_time(c, 1000, &formula));
+}
@@ -0,0 +1,981 @@
+//! マンデルブロ集合の描画
+//!
+//! コマンドラインのmandelbrotの他、別のツールやWebサービスからも描画できるようにライブラリとして公開する
+//!
+//! ```no_run
+//! use mandelbrot::{render_to_buffer, write_image, RenderParams, Viewport};
+//! use mandelbrot::palette::PixelFormat;
+//! use num::Complex;
+//!
+//! let viewport = Viewport {
+//!     bounds: (1000, 750),
+//!     upper_left: Complex { re: -1.20, im: 0.35 },
+//!     lower_right: Complex { re: -1.0, im: 0.20 },
+//! };
+//! let pixels = render_to_buffer(&viewport, &RenderParams::default());
+//! write_image("mandel.png", &pixels, viewport.bounds, PixelFormat::Gray8).unwrap();
+//! ```
+
+use std::str::FromStr;
+use num::Complex;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub mod animation;
+pub mod buddhabrot;
+mod cache;
+pub mod checkpoint;
+pub mod deep;
+pub mod distance;
+pub mod equalize;
+pub mod error;
+pub mod formula;
+pub mod interior;
+pub mod newton;
+pub mod output;
+pub mod palette;
+pub mod simd;
+pub mod tiles;
+pub mod trap;
+pub mod viewer;
+
+use deep::ReferenceOrbit;
+use error::MandelError;
+use formula::Formula;
+use palette::{Palette, PixelFormat, Sample};
+
+pub use output::{write_image, write_image_with_text};
+
+/// 描画方法の指定
+#[derive(Debug, Clone)]
+pub struct RenderParams {
+    pub palette: Palette,
+    pub format: PixelFormat,
+    /// 繰り返し回数の上限
+    pub limit: usize,
+    /// trueなら脱出時のzから求めた連続的な繰り返し回数で色付けする
+    pub smooth: bool,
+    /// Someならマンデルブロ集合の代わりに、この値をcとするジュリア集合を描画する
+    pub julia: Option<Complex<f64>>,
+    /// 繰り返し計算する漸化式
+    pub formula: Formula,
+    /// 1ピクセルを縦横samples x samples個の点で標本化し、色を平均する (アンチエイリアス)
+    pub samples: usize,
+    /// trueなら標本点を格子の各区画の中でランダムにずらす
+    pub jitter: bool,
+    /// Someなら深い拡大モード
+    /// 描画する点は参照点からのずれとして与えられ、摂動論で計算する
+    pub reference: Option<ReferenceOrbit>,
+    /// trueならカージオイドの判定と周期の検出で、集合の内部の点の計算を打ち切る
+    pub shortcuts: bool,
+    /// escape_timeの計算方法
+    pub kernel: Kernel,
+    /// trueなら繰り返し回数の代わりに、境界までの推定距離で色付けする
+    pub distance: bool,
+    /// trueなら繰り返し回数をヒストグラムの累積分布で写してから色付けする
+    pub equalize: bool,
+    /// 描画に使うスレッド数 (0なら1とみなす)
+    pub threads: usize,
+    /// 画像の中心のまわりに表示を回す角度 (ラジアン、反時計回り)
+    pub rotation: f64,
+    /// Someなら繰り返し回数の代わりに、軌道がトラップに最も近づいた距離で色付けする
+    pub trap: Option<trap::Trap>,
+    /// Someならマンデルブロ集合の代わりに、多項式のニュートン法で収束する根と回数を描く
+    pub newton: Option<newton::Newton>,
+}
+
+/// 従来どおりの8ビットグレースケール、繰り返し上限255で、CPUの数だけスレッドを使う
+impl Default for RenderParams {
+    fn default() -> RenderParams {
+        RenderParams {
+            palette: Palette::Gray,
+            format: PixelFormat::Gray8,
+            limit: 255,
+            smooth: false,
+            julia: None,
+            formula: Formula::Mandelbrot,
+            samples: 1,
+            jitter: false,
+            reference: None,
+            shortcuts: true,
+            kernel: Kernel::Scalar,
+            distance: false,
+            equalize: false,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            rotation: 0.0,
+            trap: None,
+            newton: None,
+        }
+    }
+}
+
+impl RenderParams {
+    /// 脱出したとみなす|z|の2乗 連続的な回数を使う時は半径を大きくする
+    pub fn bailout_sqr(&self) -> f64 {
+        if self.smooth { SMOOTH_BAILOUT_SQR } else { BAILOUT_SQR }
+    }
+
+    /// 実際に使うスレッド数 threadsが0でも1つは使う
+    pub fn thread_count(&self) -> usize {
+        self.threads.max(1)
+    }
+}
+
+/// 描画する画像の大きさと、画像に対応する複素平面上の範囲
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    /// 画像の幅と高さ (ピクセル数)
+    pub bounds: (usize, usize),
+    /// 画像の左上に対応する点
+    pub upper_left: Complex<f64>,
+    /// 画像の右下に対応する点
+    pub lower_right: Complex<f64>,
+}
+
+impl Viewport {
+    /// centerを中心とし、実軸方向の幅がwidthの範囲を求める
+    /// 高さは画像の縦横比に合わせるので、ピクセルは正方形になる
+    pub fn centered(bounds: (usize, usize), center: Complex<f64>, width: f64) -> Viewport {
+        let height = width * bounds.1 as f64 / bounds.0 as f64;
+        let half = Complex { re: width / 2.0, im: -height / 2.0 };
+        Viewport { bounds, upper_left: center - half, lower_right: center + half }
+    }
+
+    /// 1ピクセルの複素平面上での幅と高さの比
+    /// 1.0でなければ画像は縦か横に引き伸ばされる
+    pub fn pixel_aspect(&self) -> f64 {
+        let width = (self.lower_right.re - self.upper_left.re) / self.bounds.0 as f64;
+        let height = (self.upper_left.im - self.lower_right.im) / self.bounds.1 as f64;
+        width / height
+    }
+}
+
+/// escape_timeの計算方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kernel {
+    /// 1点ずつ計算する (基準となる実装)
+    Scalar,
+    /// simd::LANES個の点をまとめて計算する
+    Simd,
+}
+
+/// スレッドごとの処理の記録
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadStats {
+    /// 描画した行数
+    pub rows: usize,
+    /// 描画にかかった時間
+    pub busy: Duration,
+    /// 描画したピクセルについての集計
+    pub pixels: PixelStats,
+}
+
+/// 描画したピクセルについての集計
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PixelStats {
+    /// 深い拡大モードで、グリッチを避けるため参照軌道をやり直したピクセル数
+    pub rebased: usize,
+    /// カージオイドか周期2の円板の中にあると判定して計算を省いた点の数
+    pub cardioid: usize,
+    /// 周期的な軌道を検出して計算を打ち切った点の数
+    pub periodic: usize,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, other: &PixelStats) {
+        self.rebased += other.rebased;
+        self.cardioid += other.cardioid;
+        self.periodic += other.periodic;
+    }
+}
+
+/// viewportの範囲をparamsに従って描画し、ピクセルのバッファを返す
+/// バッファは1ピクセルあたりparams.format.channels()バイトで、左上から行ごとに並ぶ
+pub fn render_to_buffer(viewport: &Viewport, params: &RenderParams) -> Vec<u8> {
+    let (width, height) = viewport.bounds;
+    let mut pixels = vec![0; width * height * params.format.channels()];
+    render_image(&mut pixels, viewport.bounds, viewport.upper_left, viewport.lower_right, params);
+    pixels
+}
+
+/// 画像全体をparams.threads個のスレッドで描画する
+/// params.equalizeならrender_equalized、そうでなければrender_parallelを使う
+pub fn render_image(pixels: &mut [u8],
+            bounds: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+) -> Vec<ThreadStats> {
+    if params.equalize {
+        render_equalized(pixels, bounds, upper_left, lower_right, params)
+    } else {
+        render_parallel(pixels, bounds, upper_left, lower_right, params)
+    }
+}
+
+/// ヒストグラム平坦化して描画する
+///
+/// 1. render_parallelで色の代わりに繰り返し回数 (PixelFormat::IterF32) を求める
+/// 2. 回数のヒストグラムをスレッドごとに数えて足し合わせる
+/// 3. 累積分布で写した値をパレットで色に変換する (これもスレッドで分担する)
+fn render_equalized(pixels: &mut [u8],
+            bounds: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+) -> Vec<ThreadStats> {
+    let count_params = RenderParams { format: PixelFormat::IterF32, ..params.clone() };
+    let mut counts = vec![0; bounds.0 * bounds.1 * 4];
+    let thread_stats = render_parallel(&mut counts, bounds, upper_left, lower_right, &count_params);
+
+    let values: Vec<Option<f64>> = counts.chunks(4)
+        .map(|bytes| {
+            let count = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            if count.is_nan() { None } else { Some(count as f64) }
+        })
+        .collect();
+    let pixels_per_thread = values.len().div_ceil(params.thread_count()).max(1);
+
+    let mut histogram = equalize::Histogram::new(params.limit);
+    crossbeam::scope(|spawner| {
+        let handles: Vec<_> = values.chunks(pixels_per_thread).map(|chunk| {
+            spawner.spawn(move |_| {
+                let mut histogram = equalize::Histogram::new(params.limit);
+                for &count in chunk.iter().flatten() {
+                    histogram.add(count);
+                }
+                histogram
+            })
+        }).collect();
+        for handle in handles {
+            histogram.merge(&handle.join().unwrap());
+        }
+    }).unwrap();
+
+    let equalizer = histogram.equalizer();
+    let channels = params.format.channels();
+    crossbeam::scope(|spawner| {
+        for (chunk, values) in pixels.chunks_mut(pixels_per_thread * channels).zip(values.chunks(pixels_per_thread)) {
+            let equalizer = &equalizer;
+            spawner.spawn(move |_| {
+                for (out, value) in chunk.chunks_mut(channels).zip(values) {
+                    let sample = value.map(|count| Sample { value: count, t: equalizer.map(count) });
+                    params.palette.write_pixel(sample, params.format, out);
+                }
+            });
+        }
+    }).unwrap();
+
+    thread_stats
+}
+
+/// 画像全体をparams.threads個のスレッドで並列に描画する
+///
+/// 画像を固定の帯に分けると、集合の内部を多く含む帯だけ時間がかかり、他のスレッドが遊んでしまう
+/// そこで行のイテレータをMutexで共有し、各スレッドは手が空くたびに次の1行を取り出して描画する
+/// 戻り値はスレッドごとの処理の記録
+pub fn render_parallel(pixels: &mut [u8],
+            bounds: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+) -> Vec<ThreadStats> {
+    render_parallel_rows(pixels, bounds, upper_left, lower_right, params, 0)
+}
+
+/// 画像のfirst行目から、pixelsに収まる行数だけを並列に描画する
+/// 幅が0の画像では何もしない
+fn render_parallel_rows(pixels: &mut [u8],
+            bounds: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+            first: usize,
+) -> Vec<ThreadStats> {
+    let row_len = bounds.0 * params.format.channels();
+    if row_len == 0 {
+        return vec![ThreadStats::default(); params.thread_count()];
+    }
+    let rows = Mutex::new(pixels.chunks_mut(row_len).enumerate().map(|(i, row)| (first + i, row)));
+
+    // クロージャ
+    // crossbeam::scopeは、全ての生成されたスレッドが終了するのを待ってから終了する
+    // プログラマにとっては、corssbeam::scopeがリターンしてきたら、画像の計算が終了していることが保証される
+    crossbeam::scope(|spawner| {
+        // クロージャないで新しいスレッドを生成する
+        let handles: Vec<_> = (0..params.thread_count()).map(|_| {
+            spawner.spawn(|_| {
+                let mut stats = ThreadStats::default();
+                loop {
+                    // ロックは次の行を取り出す間だけ保持する
+                    let next = rows.lock().unwrap().next();
+                    let (top, row) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let start = Instant::now();
+                    let pixels = render_band(row, bounds, (0, top), (bounds.0, 1), upper_left, lower_right, params);
+                    stats.pixels.add(&pixels);
+                    stats.rows += 1;
+                    stats.busy += start.elapsed();
+                }
+                stats
+            })
+        }).collect();
+
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }).unwrap()
+}
+
+/// 画像を上からstrip_rows行ずつの帯に分けて描画し、帯ができるたびにwriteに渡す
+///
+/// 帯の中の行はrender_parallelと同じようにスレッドで分担する
+/// 必要なメモリは画像全体ではなく1つの帯の大きさで済むので、巨大な画像をファイルに書き出すのに使う
+/// ヒストグラム平坦化は画像全体の回数が必要なので使えない (params.equalizeならエラーを返す)
+pub fn render_strips<F>(bounds: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+            strip_rows: usize,
+            mut write: F,
+) -> Result<Vec<ThreadStats>, MandelError>
+    where F: FnMut(&[u8]) -> Result<(), MandelError>
+{
+    if params.equalize {
+        return Err(MandelError::argument("--equalize", "needs the whole image and cannot be rendered in strips"));
+    }
+    let row_len = bounds.0 * params.format.channels();
+    let mut strip = vec![0; row_len * strip_rows.min(bounds.1)];
+    let mut thread_stats = vec![ThreadStats::default(); params.thread_count()];
+
+    for first in (0..bounds.1).step_by(strip_rows.max(1)) {
+        let rows = strip_rows.min(bounds.1 - first);
+        let pixels = &mut strip[..row_len * rows];
+        let stats = render_parallel_rows(pixels, bounds, upper_left, lower_right, params, first);
+        for (total, stat) in thread_stats.iter_mut().zip(&stats) {
+            total.rows += stat.rows;
+            total.busy += stat.busy;
+            total.pixels.add(&stat.pixels);
+        }
+        write(pixels)?;
+    }
+    Ok(thread_stats)
+}
+
+/// 大きさboundsの画像を、viewsの範囲 (左上と右下) ごとに続けて描画する
+///
+/// render_imageを繰り返し呼ぶとフレームごとにスレッドを作り直すので、
+/// 最初にparams.thread_count()個のスレッドを作り、全てのフレームの行を同じスレッドに割り振る
+/// 行はチャネルで送り、描き終えた行を受け取ってフレームのバッファに並べる
+/// フレームが揃うたびに、番号とピクセルとスレッドごとの記録をwriteに渡す
+/// ヒストグラム平坦化は画像全体で2回に分けて描くので、フレームごとにrender_imageを使う
+pub fn render_sequence<I, F>(bounds: (usize, usize),
+            views: I,
+            params: &RenderParams,
+            mut write: F,
+) -> Result<(), MandelError>
+    where I: IntoIterator<Item = (Complex<f64>, Complex<f64>)>,
+          F: FnMut(usize, &[u8], &[ThreadStats]) -> Result<(), MandelError>
+{
+    let row_len = bounds.0 * params.format.channels();
+    let mut pixels = vec![0; row_len * bounds.1];
+    if params.equalize {
+        for (frame, (upper_left, lower_right)) in views.into_iter().enumerate() {
+            let thread_stats = render_image(&mut pixels, bounds, upper_left, lower_right, params);
+            write(frame, &pixels, &thread_stats)?;
+        }
+        return Ok(());
+    }
+
+    let threads = params.thread_count();
+    crossbeam::scope(|spawner| {
+        let (jobs, job_receiver) = crossbeam::channel::unbounded::<(usize, Complex<f64>, Complex<f64>, Vec<u8>)>();
+        let (done_sender, done) = crossbeam::channel::unbounded();
+        for worker in 0..threads {
+            let (job_receiver, done_sender) = (job_receiver.clone(), done_sender.clone());
+            spawner.spawn(move |_| {
+                // 送る側がjobsを閉じるまで、次の行を受け取って描画する
+                for (top, upper_left, lower_right, mut row) in job_receiver {
+                    let start = Instant::now();
+                    let stats = render_band(&mut row, bounds, (0, top), (bounds.0, 1), upper_left, lower_right, params);
+                    if done_sender.send((worker, top, row, stats, start.elapsed())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let render_frames = || {
+            for (frame, (upper_left, lower_right)) in views.into_iter().enumerate() {
+                for top in 0..bounds.1 {
+                    jobs.send((top, upper_left, lower_right, vec![0; row_len])).expect("render threads are running");
+                }
+                let mut thread_stats = vec![ThreadStats::default(); threads];
+                for _ in 0..bounds.1 {
+                    let (worker, top, row, stats, busy) = done.recv().expect("render threads are running");
+                    pixels[top * row_len..(top + 1) * row_len].copy_from_slice(&row);
+                    thread_stats[worker].rows += 1;
+                    thread_stats[worker].busy += busy;
+                    thread_stats[worker].pixels.add(&stats);
+                }
+                write(frame, &pixels, &thread_stats)?;
+            }
+            Ok(())
+        };
+        let result = render_frames();
+        // jobsを閉じるとスレッドが終了する
+        drop(jobs);
+        result
+    }).unwrap()
+}
+
+/// sが適切な形であればSome<(x,y)>を返す　そうでなければNone
+/// <T: FromStr> は FromStrトレイトを実装する任意の型Tに対して　と読む
+/// Option<(T, T)> NoneかSome((v1, v2))の値となる。 (v1, v2)は型Tの値2つのタプル
+pub fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
+
+    // 文字列の中からseparatorに合致する文字を探す。
+    // findがNoneを返す場合は、セパレータ文字が文字列には現れなかったことを意味し、Noneを返し、パース失敗を表す
+    match s.find(separator) {
+        None => None,
+        Some(index) => {
+            // indexはseparator文字の位置を表す
+            // separatorの文字の前後を取り出した文字列のスライスをとり、型Tのタプルを作る
+            // これに対してマッチングを行う
+            // _は何にでもマッチし、その値を無視する
+            match (T::from_str(&s[..index]), T::from_str(&s[index +1..])) {
+                (Ok(l), Ok(r)) => Some((l, r)), // 双方のパースが成功した場合
+                _ => None
+            }
+        }
+    }
+}
+
+/// カンマで分けられたfloatのペアをパースして複素数を返す
+pub fn parse_complex(s: &str) -> Option<Complex<f64>> {
+    parse_pair(s, ',').map(|(re, im)| Complex {re, im})
+}
+
+/// 脱出したとみなす|z|の2乗 (半径2)
+pub const BAILOUT_SQR: f64 = 4.0;
+
+/// 連続的な繰り返し回数を求める時に脱出したとみなす|z|の2乗 (半径256)
+/// smooth_countは脱出半径が十分大きい時にだけ回数の変わり目で連続になる
+/// 半径2のままでは、回数の変わり目で値が0.6回分ほど飛んで縞模様が残る
+pub const SMOOTH_BAILOUT_SQR: f64 = 65536.0;
+
+/// 半径2 (--smoothでは半径256) の円から出た点の情報
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Escape {
+    /// 円から出るまでにかかった繰り返し回数
+    pub count: usize,
+    /// 円から出た時点のzの値
+    pub z: Complex<f64>,
+}
+
+impl Escape {
+    /// 脱出時の|z|を使って繰り返し回数を連続的な値にする (normalized iteration count)
+    /// 整数の回数で色付けした時に見える縞模様をなくすために使う
+    /// 連続になるのはSMOOTH_BAILOUT_SQRで判定した場合
+    /// degreeは漸化式の次数 (z^2 + cなら2)
+    pub fn smooth_count(&self, degree: f64) -> f64 {
+        let log_zn = self.z.norm_sqr().ln() / 2.0;
+        let nu = (log_zn / std::f64::consts::LN_2).ln() / degree.ln();
+        self.count as f64 + 1.0 - nu
+    }
+}
+
+///
+///limitを繰り返し回数の上限として、cがマンデルブロ集合 (formulaが他の漸化式ならその集合) に含まれるかを判定する
+///
+/// cがマンデルブロ集合に含まれないならSome(escape)を返す
+/// escape.countはcが原点を中心とする半径2の縁から出るまでにかかった繰り返し回数、escape.zはその時のzとなる
+/// cがマンデルブロ集合に含まれているらしい(繰り返し上限に達しても、cがマンデルブロ集合に含まれないことを示せなかった場合)
+/// Noneを返す
+/// 
+/// 戻り値はOption<Escape>
+pub fn escape_time(c: Complex<f64>, limit: usize, formula: &Formula) -> Option<Escape> {
+    escape_time_from(Complex {re: 0.0, im: 0.0}, c, limit, formula)
+}
+
+/// zを初期値としてformulaの漸化式を繰り返し、escape_timeと同様に判定する
+/// cを固定してzを画素ごとに変えるとジュリア集合になる
+pub fn escape_time_from(z: Complex<f64>, c: Complex<f64>, limit: usize, formula: &Formula) -> Option<Escape> {
+    escape_time_bailout(z, c, limit, formula, BAILOUT_SQR)
+}
+
+/// escape_time_fromと同じだが、|z|の2乗がbailout_sqrを超えたら脱出したとみなす
+pub fn escape_time_bailout(mut z: Complex<f64>, c: Complex<f64>, limit: usize, formula: &Formula, bailout_sqr: f64)
+    -> Option<Escape> {
+    for i in 0..limit {
+
+        // 半径2 (bailout_sqrの平方根) の円からでたかどうか
+        // zの原点からの距離の2乗
+        if z.norm_sqr() > bailout_sqr {
+            return Some(Escape { count: i, z });
+        }
+        z = formula.step(z, c);
+    }
+    None
+}
+
+/// 出力される画像のピクセルの位置をとり、対応する複素平面上の点を返す
+/// pixelは画像上の特定のピクセルを（行,列)ペアの形で指定する
+/// 仮引数upper_left lower_rightは出力画像に描画する複素平面を左上と右下で指定する
+pub fn pixel_to_point(bounds: (usize, usize),
+                    pixel: (usize, usize),
+                    upper_left: Complex<f64>,
+                    lower_right: Complex<f64>) -> Complex<f64> {
+    let (width, height) = (lower_right.re - upper_left.re, upper_left.im - lower_right.im);
+
+    // imが引き算となっている理由。 上に動くとpixel.1は増えるが、虚部は小さくなるため
+    // pixel.0 pixel.1はタプルの要素を参照
+    Complex {
+        re: upper_left.re + pixel.0 as f64 * width / bounds.0 as f64,
+        im: upper_left.im - pixel.1 as f64 * height / bounds.1 as f64
+    }
+}
+
+/// pixel_to_pointの逆 複素平面上の点pointを含むピクセルの位置を返す
+/// 画像の外の点ならNone
+pub fn point_to_pixel(bounds: (usize, usize),
+                    point: Complex<f64>,
+                    upper_left: Complex<f64>,
+                    lower_right: Complex<f64>) -> Option<(usize, usize)> {
+    let x = (point.re - upper_left.re) / (lower_right.re - upper_left.re) * bounds.0 as f64;
+    let y = (upper_left.im - point.im) / (upper_left.im - lower_right.im) * bounds.1 as f64;
+    // NaNもここで除かれる
+    if (0.0..bounds.0 as f64).contains(&x) && (0.0..bounds.1 as f64).contains(&y) {
+        Some((x as usize, y as usize))
+    } else {
+        None
+    }
+}
+
+/// 矩形範囲のマンデルブロ集合をピクセルのバッファに描画する
+/// pixelsは1ピクセルあたりparams.format.channels()バイトで、脱出までの回数をパレットで色に変換して書き込む
+pub fn render(pixels: &mut [u8],
+            bounds: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+) -> PixelStats {
+    render_band(pixels, bounds, (0, 0), bounds, upper_left, lower_right, params)
+}
+
+/// 大きさboundsの画像のうち、originを左上とする大きさsizeの部分を描画する
+/// 点は画像全体でのピクセルの位置から求めるので、どう分けて描いても画像全体を1度に描いた時と同じ結果になる
+/// params.rotationによる回転も、部分ではなく画像全体の中心のまわりに行う
+pub(crate) fn render_band(pixels: &mut [u8],
+            bounds: (usize, usize),
+            origin: (usize, usize),
+            size: (usize, usize),
+            upper_left: Complex<f64>,
+            lower_right: Complex<f64>,
+            params: &RenderParams,
+) -> PixelStats {
+    let channels = params.format.channels();
+    assert!(pixels.len() == size.0 * size.1 * channels);
+
+    let mut stats = PixelStats::default();
+
+    // 1ピクセルの複素平面上での幅と高さ
+    let pixel_size = ((lower_right.re - upper_left.re) / bounds.0 as f64,
+                      (upper_left.im - lower_right.im) / bounds.1 as f64);
+    let n = params.samples;
+    let simd = params.kernel == Kernel::Simd && n == 1 && params.reference.is_none()
+        && params.formula == Formula::Mandelbrot && !params.distance && params.trap.is_none()
+        && params.newton.is_none();
+
+    let center = (upper_left + lower_right) / 2.0;
+    let turn = Complex::from_polar(1.0, params.rotation);
+    let view = |point: Complex<f64>| {
+        if params.rotation == 0.0 { point } else { center + (point - center) * turn }
+    };
+
+    for row in 0..size.1 {
+        let start = row * size.0 * channels;
+        let row_pixels = &mut pixels[start..start + size.0 * channels];
+        let top = origin.1 + row;
+        if simd {
+            let point = |column: usize| view(pixel_to_point(bounds, (origin.0 + column, top), upper_left, lower_right));
+            render_row_simd(row_pixels, point, pixel_size.0, params, &mut stats);
+            continue;
+        }
+
+        for column in 0..size.0 {
+            let point = pixel_to_point(bounds, (origin.0 + column, top),
+                upper_left, lower_right);
+            let out = &mut row_pixels[column * channels..(column + 1) * channels];
+
+            if n == 1 {
+                params.palette.write_pixel(point_sample(view(point), params, pixel_size.0, &mut stats), params.format, out);
+                continue;
+            }
+
+            // ピクセル内のn x n個の点をそれぞれ色に変換し、チャンネルごとに平均する
+            let mut sum = [0.0; 4];
+            let mut sub_pixel = [0; 4];
+            for i in 0..n * n {
+                let (mut dx, mut dy) = (0.5, 0.5);
+                if params.jitter {
+                    (dx, dy) = jitter(point, i);
+                }
+                let sub_point = Complex {
+                    re: point.re + ((i % n) as f64 + dx) / n as f64 * pixel_size.0,
+                    im: point.im - ((i / n) as f64 + dy) / n as f64 * pixel_size.1,
+                };
+                params.palette.write_pixel(point_sample(view(sub_point), params, pixel_size.0, &mut stats), params.format, &mut sub_pixel[..channels]);
+                for (total, value) in sum.iter_mut().zip(params.format.decode(&sub_pixel[..channels])) {
+                    *total += value;
+                }
+            }
+            params.format.encode(sum.map(|total| total / (n * n) as f64), out);
+        }
+    }
+    stats
+}
+
+/// 1行分をsimd::LANES個ずつまとめて計算する
+/// 割り切れずに余った右端のピクセルは1点ずつ計算する
+/// pointは列の番号から、回転を適用した点を返す
+fn render_row_simd(pixels: &mut [u8],
+            point: impl Fn(usize) -> Complex<f64>,
+            pixel_width: f64,
+            params: &RenderParams,
+            stats: &mut PixelStats,
+) {
+    use simd::LANES;
+
+    let channels = params.format.channels();
+    let width = pixels.len() / channels;
+    let zero = Complex {re: 0.0, im: 0.0};
+    let full = width / LANES * LANES;
+
+    for column in (0..full).step_by(LANES) {
+        let mut points = [zero; LANES];
+        for (lane, lane_point) in points.iter_mut().enumerate() {
+            *lane_point = point(column + lane);
+        }
+
+        // カージオイド内の点がレーンに1つでもあると、全レーンが繰り返し上限まで回ってしまう
+        // そこで、その点は最初の繰り返しで脱出する点に差し替えて計算し、結果は集合の内部とする
+        let mut inside = [false; LANES];
+        if params.shortcuts && params.julia.is_none() {
+            inside = points.map(interior::in_cardioid_or_bulb);
+            stats.cardioid += inside.iter().filter(|&&inside| inside).count();
+        }
+
+        let escapes = match params.julia {
+            Some(c) => simd::escape_time_lanes(points, [c; LANES], params.limit, params.bailout_sqr()),
+            None => {
+                let c = std::array::from_fn(|lane| if inside[lane] { Complex {re: 4.0, im: 0.0} } else { points[lane] });
+                simd::escape_time_lanes([zero; LANES], c, params.limit, params.bailout_sqr())
+            }
+        };
+
+        for lane in 0..LANES {
+            let escape = if inside[lane] { None } else { escapes[lane] };
+            let offset = (column + lane) * channels;
+            params.palette.write_pixel(escape_sample(escape, params), params.format,
+                                        &mut pixels[offset..offset + channels]);
+        }
+    }
+
+    for column in full..width {
+        let offset = column * channels;
+        params.palette.write_pixel(point_sample(point(column), params, pixel_width, stats), params.format,
+                                    &mut pixels[offset..offset + channels]);
+    }
+}
+
+/// 点pointの計算結果をparamsに従って求める
+/// 集合に含まれる場合 (ニュートン法では収束しない場合) はNone
+/// 深い拡大モードではpointは参照点からのずれとなる
+/// pixel_widthは1ピクセルの複素平面上での幅で、距離推定の色付けに使う
+fn point_sample(point: Complex<f64>, params: &RenderParams, pixel_width: f64, stats: &mut PixelStats) -> Option<Sample> {
+    if let Some(newton) = &params.newton {
+        return newton.sample(point, params.limit);
+    }
+    if params.distance {
+        return distance_sample(point, params, pixel_width, stats);
+    }
+    if let Some(trap) = &params.trap {
+        return Some(trap_sample(point, trap, params));
+    }
+
+    let bailout_sqr = params.bailout_sqr();
+    let escape = match (&params.reference, params.julia) {
+        (Some(reference), _) => {
+            let (escape, rebased) = reference.escape_time(point, params.limit, bailout_sqr);
+            if rebased {
+                stats.rebased += 1;
+            }
+            escape
+        }
+        (None, Some(c)) if params.shortcuts => {
+            let (escape, periodic) = interior::escape_time_periodic(point, c, params.limit, &params.formula, bailout_sqr);
+            stats.periodic += periodic as usize;
+            escape
+        }
+        (None, None) if params.shortcuts => {
+            if params.formula == Formula::Mandelbrot && interior::in_cardioid_or_bulb(point) {
+                stats.cardioid += 1;
+                return None;
+            }
+            let zero = Complex {re: 0.0, im: 0.0};
+            let (escape, periodic) = interior::escape_time_periodic(zero, point, params.limit, &params.formula, bailout_sqr);
+            stats.periodic += periodic as usize;
+            escape
+        }
+        (None, Some(c)) => escape_time_bailout(point, c, params.limit, &params.formula, bailout_sqr),
+        (None, None) => escape_time_bailout(Complex {re: 0.0, im: 0.0}, point, params.limit, &params.formula, bailout_sqr),
+    };
+    escape_sample(escape, params)
+}
+
+/// escape_timeの結果を色付けに使う繰り返し回数にする
+fn escape_sample(escape: Option<Escape>, params: &RenderParams) -> Option<Sample> {
+    escape.map(|escape| {
+        let count = if params.smooth { escape.smooth_count(params.formula.degree()) } else { escape.count as f64 };
+        Sample::count(count, params.limit)
+    })
+}
+
+/// 境界までの推定距離で色付けする
+/// 距離をピクセル数に直し、境界に近いほどtを1.0 (グレースケールでは黒) に近づける
+/// 1ピクセル離れると0.5、10ピクセル離れると0.09程度になる
+fn distance_sample(point: Complex<f64>, params: &RenderParams, pixel_width: f64, stats: &mut PixelStats) -> Option<Sample> {
+    let distance = match params.julia {
+        Some(c) => distance::distance_estimate(point, c, params.limit, &params.formula, true),
+        None => {
+            if params.shortcuts && params.formula == Formula::Mandelbrot && interior::in_cardioid_or_bulb(point) {
+                stats.cardioid += 1;
+                return None;
+            }
+            distance::distance_estimate(Complex {re: 0.0, im: 0.0}, point, params.limit, &params.formula, false)
+        }
+    };
+    distance.map(|distance| Sample { value: distance, t: 1.0 / (1.0 + distance / pixel_width.abs()) })
+}
+
+/// 軌道がトラップに最も近づいた距離で色付けする
+/// 内部の点も軌道が模様になるので、カージオイドや周期の検出で打ち切らずに上限まで繰り返す
+/// トラップに近いほどtを0.0 (グレースケールでは白) に近づける 距離0.25で0.5になる
+fn trap_sample(point: Complex<f64>, trap: &trap::Trap, params: &RenderParams) -> Sample {
+    let (z, c) = match params.julia {
+        Some(c) => (point, c),
+        None => (Complex {re: 0.0, im: 0.0}, point),
+    };
+    let distance = trap::trap_distance(z, c, params.limit, &params.formula, trap);
+    Sample { value: distance, t: distance / (distance + 0.25) }
+}
+
+/// ピクセルの位置と標本の番号から、区画内でのずらし量 (0.0〜1.0の組) を求める
+/// 同じ画像を描き直した時に結果が変わらないよう、乱数の代わりに位置から作った種のsplitmix64を使う
+fn jitter(point: Complex<f64>, sample: usize) -> (f64, f64) {
+    let mut random = SplitMix64(point.re.to_bits() ^ point.im.to_bits().rotate_left(32)
+                                ^ (sample as u64).wrapping_mul(0x9e3779b97f4a7c15));
+    (random.next_f64(), random.next_f64())
+}
+
+/// splitmix64による疑似乱数
+/// 種が同じなら同じ列になるので、描き直しても結果が変わらない
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    /// [0, 1)の一様な値を返す
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        // 上位53ビットを[0, 1)の浮動小数点数にする
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[test]
+fn test_parse_pair() {
+    assert_eq!(parse_pair::<i32>("", ','), None);
+    assert_eq!(parse_pair::<i32>("10", ','), None);
+    assert_eq!(parse_pair::<i32>(",10", ','), None);
+    assert_eq!(parse_pair::<i32>("10,20", ','), Some((10, 20)));
+    assert_eq!(parse_pair::<i32>("10,20xy", ','), None);
+    assert_eq!(parse_pair::<f64>("0.5x", 'x'), None);
+    assert_eq!(parse_pair::<f64>("0.5x1.5", 'x'), Some((0.5, 1.5)));
+}
+
+#[test]
+fn test_parse_complex() {
+    assert_eq!(parse_complex("1.25,-0.0625"), Some(Complex {re: 1.25, im: -0.0625}));
+    assert_eq!(parse_complex(", -0.0625"), None);
+}
+
+#[test]
+fn test_pixel_to_point() {
+    assert_eq!(pixel_to_point((100, 200), (25, 175),
+                                Complex {re: -1.0, im: 1.0},
+                                Complex {re: 1.0, im: -1.0}),
+                                Complex {re: -0.5, im: -0.75});
+}
+
+#[test]
+fn test_point_to_pixel() {
+    let (upper_left, lower_right) = (Complex {re: -1.0, im: 1.0}, Complex {re: 1.0, im: -1.0});
+    assert_eq!(point_to_pixel((100, 200), Complex {re: -0.5, im: -0.75}, upper_left, lower_right), Some((25, 175)));
+    assert_eq!(point_to_pixel((100, 200), Complex {re: -0.49, im: -0.755}, upper_left, lower_right), Some((25, 175)));
+    assert_eq!(point_to_pixel((100, 200), Complex {re: 1.0, im: 0.0}, upper_left, lower_right), None);
+    assert_eq!(point_to_pixel((100, 200), Complex {re: 0.0, im: f64::NAN}, upper_left, lower_right), None);
+}
+
+#[test]
+fn test_escape_time() {
+    assert_eq!(escape_time(Complex {re: 0.0, im: 0.0}, 255, &Formula::Mandelbrot), None);
+    let escape = escape_time(Complex {re: 1.0, im: 0.0}, 255, &Formula::Mandelbrot).unwrap();
+    assert_eq!(escape, Escape { count: 3, z: Complex {re: 5.0, im: 0.0} });
+    // 連続化した回数は整数の回数の近くに収まる
+    let smooth = escape.smooth_count(2.0);
+    assert!(smooth > 2.0 && smooth < 4.0);
+}
+
+#[test]
+fn test_smooth_count_is_continuous() {
+    // Im c = 1.0の直線上で、整数の回数が変わる隣り合った点の連続化した回数を比べる
+    let max_jump = |bailout_sqr: f64| {
+        let smooth = |re: f64| {
+            let c = Complex {re, im: 1.0};
+            let escape = escape_time_bailout(Complex {re: 0.0, im: 0.0}, c, 1000, &Formula::Mandelbrot, bailout_sqr);
+            escape.map(|escape| (escape.count, escape.smooth_count(2.0)))
+        };
+        let mut max_jump: f64 = 0.0;
+        let mut previous = smooth(0.1).unwrap();
+        for i in 1..=20000 {
+            let next = smooth(0.1 + i as f64 * 1e-5).unwrap();
+            if next.0 != previous.0 {
+                max_jump = max_jump.max((next.1 - previous.1).abs());
+            }
+            previous = next;
+        }
+        max_jump
+    };
+    assert!(max_jump(SMOOTH_BAILOUT_SQR) < 0.01, "{}", max_jump(SMOOTH_BAILOUT_SQR));
+    // 半径2では回数の変わり目で値が飛ぶ
+    assert!(max_jump(BAILOUT_SQR) > 0.1, "{}", max_jump(BAILOUT_SQR));
+}
+
+#[test]
+fn test_escape_time_julia() {
+    // c = 0のジュリア集合は単位円板
+    let c = Complex {re: 0.0, im: 0.0};
+    assert_eq!(escape_time_from(Complex {re: 0.5, im: 0.5}, c, 255, &Formula::Mandelbrot), None);
+    assert_eq!(escape_time_from(Complex {re: 1.5, im: 0.0}, c, 255, &Formula::Mandelbrot).map(|e| e.count), Some(1));
+}
+
+#[test]
+fn test_render_to_buffer() {
+    let viewport = Viewport {
+        bounds: (40, 30),
+        upper_left: Complex {re: -2.0, im: 1.2},
+        lower_right: Complex {re: 1.0, im: -1.2},
+    };
+    let params = RenderParams { palette: Palette::fire(), format: PixelFormat::Rgb8, threads: 3, ..RenderParams::default() };
+    let pixels = render_to_buffer(&viewport, &params);
+    assert_eq!(pixels.len(), 40 * 30 * 3);
+
+    // スレッド数を変えても結果は変わらない
+    let single = render_to_buffer(&viewport, &RenderParams { threads: 1, ..params.clone() });
+    assert_eq!(pixels, single);
+    assert_eq!(render_to_buffer(&viewport, &RenderParams { threads: 0, ..params.clone() }), single);
+
+    // 幅や高さが0の画像は空のバッファになる
+    for bounds in [(0, 30), (40, 0), (0, 0)] {
+        assert!(render_to_buffer(&Viewport { bounds, ..viewport }, &params).is_empty());
+        assert!(render_to_buffer(&Viewport { bounds, ..viewport }, &RenderParams { equalize: true, ..params.clone() }).is_empty());
+    }
+
+    // 複素微分できない漸化式で距離推定を指定しても、パニックせずに描画する
+    let burning_ship = RenderParams { distance: true, formula: Formula::BurningShip, ..params.clone() };
+    assert_eq!(render_to_buffer(&viewport, &burning_ship).len(), pixels.len());
+}
+
+#[test]
+fn test_viewport_centered() {
+    let view = Viewport::centered((400, 300), Complex {re: -1.1, im: 0.275}, 0.2);
+    assert_eq!(view.bounds, (400, 300));
+    assert!((view.upper_left - Complex {re: -1.2, im: 0.35}).norm() < 1e-12);
+    assert!((view.lower_right - Complex {re: -1.0, im: 0.2}).norm() < 1e-12);
+    assert!((view.pixel_aspect() - 1.0).abs() < 1e-12);
+
+    let stretched = Viewport { bounds: (400, 300), upper_left: Complex {re: -1.2, im: 0.35}, lower_right: Complex {re: -1.0, im: 0.0} };
+    assert!((stretched.pixel_aspect() - 3.0 / 7.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_render_rotation() {
+    let bounds = (21, 10);
+    let (upper_left, lower_right) = (Complex {re: -1.5, im: 0.7}, Complex {re: 0.5, im: -0.3});
+    let params = RenderParams { threads: 2, ..RenderParams::default() };
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    render_image(&mut pixels, bounds, upper_left, lower_right, &params);
+
+    let rotated_params = RenderParams { rotation: 0.5, ..params };
+    let mut rotated = vec![0; bounds.0 * bounds.1];
+    render_image(&mut rotated, bounds, upper_left, lower_right, &rotated_params);
+    assert_ne!(pixels, rotated);
+
+    // 行ごとに描画しても画像全体の中心のまわりに回すので、1枚で描画した場合と一致する
+    // SIMDの経路でも同じ点を計算する
+    let mut whole = vec![0; bounds.0 * bounds.1];
+    render(&mut whole, bounds, upper_left, lower_right, &rotated_params);
+    assert_eq!(whole, rotated);
+    let mut simd = vec![0; bounds.0 * bounds.1];
+    render_image(&mut simd, bounds, upper_left, lower_right, &RenderParams { kernel: Kernel::Simd, ..rotated_params });
+    assert_eq!(simd, rotated);
+}
+
+#[test]
+fn test_render_strips_matches_render_image() {
+    let bounds = (30, 23);
+    let (upper_left, lower_right) = (Complex {re: -2.0, im: 1.2}, Complex {re: 1.0, im: -1.2});
+    let params = RenderParams { palette: Palette::fire(), format: PixelFormat::Rgb8, threads: 3, rotation: 0.3, ..RenderParams::default() };
+    let mut pixels = vec![0; bounds.0 * bounds.1 * 3];
+    render_image(&mut pixels, bounds, upper_left, lower_right, &params);
+
+    // 帯の行数が画像の高さを割り切れなくても、最後の帯で残りの行を描く
+    let mut streamed = Vec::new();
+    let thread_stats = render_strips(bounds, upper_left, lower_right, &params, 5, |rows| {
+        assert!(rows.len() <= 5 * bounds.0 * 3);
+        streamed.extend_from_slice(rows);
+        Ok(())
+    }).unwrap();
+    assert_eq!(streamed, pixels);
+    assert_eq!(thread_stats.iter().map(|stats| stats.rows).sum::<usize>(), bounds.1);
+
+    // ヒストグラム平坦化は帯ごとにはできないので、panicせずエラーにする
+    let equalize = RenderParams { equalize: true, ..params };
+    assert!(render_strips(bounds, upper_left, lower_right, &equalize, 5, |_| Ok(())).is_err());
+}
+
+#[test]
+fn test_render_sequence_matches_render_image() {
+    let bounds = (24, 17);
+    let views = [(Complex {re: -2.0, im: 1.2}, Complex {re: 1.0, im: -1.2}),
+                 (Complex {re: -1.0, im: 0.5}, Complex {re: 0.0, im: -0.2})];
+    for equalize in [false, true] {
+        let params = RenderParams { palette: Palette::fire(), format: PixelFormat::Rgb8, threads: 3, equalize, ..RenderParams::default() };
+        let mut frames = Vec::new();
+        render_sequence(bounds, views, &params, |frame, pixels, thread_stats| {
+            assert_eq!(thread_stats.iter().map(|stats| stats.rows).sum::<usize>(), bounds.1);
+            frames.push((frame, pixels.to_vec()));
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(frames.len(), views.len());
+        for ((frame, pixels), &(upper_left, lower_right)) in frames.iter().zip(&views) {
+            let viewport = Viewport { bounds, upper_left, lower_right };
+            assert_eq!(pixels, &render_to_buffer(&viewport, &params), "frame {}", frame);
+        }
+    }
+}
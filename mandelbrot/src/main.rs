@@ -1,169 +1,764 @@
 use std::str::FromStr;
 use num::Complex;
-use image::ColorType;
-use image::png::PNGEncoder;
-use std::fs::File;
 use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
+use mandelbrot::animation::{self, Animation};
+use mandelbrot::buddhabrot::{Buddhabrot, OrbitStats};
+use mandelbrot::checkpoint::{self, Checkpoint};
+use mandelbrot::deep::{self, Decimal};
+use mandelbrot::error::MandelError;
+use mandelbrot::formula::Formula;
+use mandelbrot::newton::Newton;
+use mandelbrot::output::{self, read_text, OutputFormat, PngStream, TEXT_PREFIX};
+use mandelbrot::palette::{Palette, PixelFormat};
+use mandelbrot::tiles::{TileMap, TileServer};
+use mandelbrot::viewer::{Graphics, View, Viewer};
+use mandelbrot::{parse_complex, parse_pair, render_image, render_sequence, render_strips, write_image_with_text, Kernel, PixelStats, RenderParams, ThreadStats, Viewport};
 
-fn main() {
+/// コマンドライン引数
+#[derive(Debug)]
+struct Arguments {
+    command: Command,
+    filename: String,
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    params: RenderParams,
+    /// trueなら描画後にスレッドごとの処理時間を表示する
+    stats: bool,
+    /// 深い拡大モードでは、範囲の座標をf64に丸めず10進数のまま保持する
+    deep: Option<DeepView>,
+    /// Someならアニメーションの各フレームを連番のファイルに書き出す
+    animation: Option<Animation>,
+    /// SomeならエスケープタイムではなくBuddhabrotを描画する
+    buddhabrot: Option<Buddhabrot>,
+    /// Someなら画像全体を確保せず、この行数ずつ描画してPNGファイルに流し込む
+    strip_rows: Option<usize>,
+    /// Someならタイルごとにディレクトリに保存しながら描画する
+    checkpoint: Option<Checkpoint>,
+    /// 描画の結果に関わる引数をつなげた文字列
+    /// 保存したタイルが同じ描画のものかどうかの照合に使う
+    key: String,
+}
 
-    let args: Vec<String> = env::args().collect();
+/// 最初の引数で選ぶ動作
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    /// 画像を描画してファイルに書き出す
+    Render,
+    /// 端末の中で移動や拡大をしながら眺める
+    View(Graphics),
+    /// 指定したポートでタイルを返すHTTPサーバを動かす
+    Serve(u16),
+}
+
+/// 深い拡大モードでの範囲の指定
+#[derive(Debug)]
+enum DeepView {
+    /// 左上と右下の角
+    Corners((Decimal, Decimal), (Decimal, Decimal)),
+    /// 中心 (upper_left lower_rightは中心からのずれとして保持する)
+    Center((Decimal, Decimal)),
+}
 
-    if args.len() != 5 {
-        eprintln!("Usage: {} FILE PIXELS UPPERLEFT LOWERRIGHT", args[0]);
-        eprintln!("Example: {} mandel.png 1000x750 -1.20,0.35 -1,0.20", args[0]);
-        std::process::exit(1);
+fn print_usage(program: &str) {
+    eprintln!("Usage: {} [OPTIONS] FILE PIXELS UPPERLEFT LOWERRIGHT", program);
+    eprintln!("       {} [OPTIONS] --center RE,IM [--zoom Z | --width W] FILE PIXELS", program);
+    eprintln!("       {} [OPTIONS] --from IMAGE FILE [PIXELS]", program);
+    eprintln!("       {} view [OPTIONS] [FILE [PIXELS] [UPPERLEFT LOWERRIGHT]]", program);
+    eprintln!("       {} serve [OPTIONS] [CACHEDIR]", program);
+    eprintln!("Example: {} mandel.png 1000x750 -1.20,0.35 -1,0.20", program);
+    eprintln!("The extension of FILE selects the output: .png (default), .pgm/.ppm, .tif/.tiff,");
+    eprintln!(".raw (\"MBIT\", u32 width, u32 height, then f32 counts; all little-endian) or .npy");
+    eprintln!("(.raw and .npy write iteration counts unless --depth is given)");
+    eprintln!("PNG files record the view and coloring, which --from reads back.");
+    eprintln!("view explores the set in the terminal: arrow keys (or hjkl) move, + and - zoom,");
+    eprintln!("] and [ double and halve the iteration limit, s saves the view to FILE (default");
+    eprintln!("view.png, numbered as view0000.png, ...) at PIXELS (default 1280x720), q quits.");
+    eprintln!("Without --center or corners it starts from the whole set.");
+    eprintln!("serve answers /tiles/{{z}}/{{x}}/{{y}}.png on http://127.0.0.1:PORT/ for web map viewers");
+    eprintln!("(the page at / shows them with Leaflet). Zoom level 0 is one square tile of the");
+    eprintln!("--center/--width view (default: the whole set); tiles are cached in CACHEDIR (default tiles).");
+    eprintln!("Options:");
+    eprintln!("  --from IMAGE       render again with the size, corners and every coloring option");
+    eprintln!("                     stored in IMAGE; the command line overrides them");
+    eprintln!("  --center RE,IM     center of the view instead of the corners; the height");
+    eprintln!("                     follows the aspect ratio of PIXELS so pixels stay square");
+    eprintln!("  --zoom Z           magnification for --center: the view is 4/Z wide (default 1)");
+    eprintln!("  --width W          width of the view on the real axis for --center");
+    eprintln!("  --rotate DEG       rotate the view counterclockwise around its center");
+    eprintln!("  --palette NAME     gray (default), ultra, fire, hsv");
+    eprintln!("  --gradient STOPS   user gradient, e.g. 0:000764,0.5:ff0000,1:ffffff");
+    eprintln!("  --alpha            write RGBA with the set interior transparent");
+    eprintln!("  --smooth           color by the fractional iteration count to avoid banding");
+    eprintln!("  --max-iter N       iteration limit (default 255)");
+    eprintln!("  --depth DEPTH      8 (default), 16 for 16-bit grayscale,");
+    eprintln!("                     or float to write f32 iteration counts (.raw, .npy or .tif FILE only)");
+    eprintln!("  --julia RE,IM      render the Julia set for the constant c = RE+IMi");
+    eprintln!("  --formula NAME     mandelbrot (default), burning-ship, tricorn, multibrot:N");
+    eprintln!("  --supersample N    average NxN samples per pixel (anti-aliasing)");
+    eprintln!("  --jitter           randomize the supersample positions within each cell");
+    eprintln!("  --deep             deep zoom: parse the corners or --center with arbitrary precision and");
+    eprintln!("                     render by perturbation around the view center (mandelbrot only)");
+    eprintln!("  --no-shortcuts     always iterate up to the limit (disable cardioid/bulb");
+    eprintln!("                     checks and periodicity detection for interior points)");
+    eprintln!("  --kernel NAME      scalar (default) or simd to iterate several pixels of a row");
+    eprintln!("                     in lockstep (mandelbrot without --deep/--supersample only)");
+    eprintln!("  --distance         shade by the estimated distance to the boundary (line art)");
+    eprintln!("  --equalize         histogram-equalized coloring (two passes: counts, then colors)");
+    eprintln!("  --trap SHAPE       color by the closest approach of each orbit to a shape:");
+    eprintln!("                     point:RE,IM, line:RE,IM:DEG, cross:RE,IM or circle:RE,IM:R");
+    eprintln!("                     (a bare name is centered on the origin)");
+    eprintln!("  --newton COEFFS    render Newton's method for the polynomial with the given coefficients,");
+    eprintln!("                     highest degree first: 1:0:0:-1 is z^3 - 1 (a coefficient may be RE,IM);");
+    eprintln!("                     colored by the root reached, shaded by the iterations taken");
+    eprintln!("  --buddhabrot       plot the density of escaping orbits of random points instead");
+    eprintln!("                     (iterated up to --max-iter and colored by --palette)");
+    eprintln!("  --nebulabrot R,G,B Buddhabrot with three iteration limits mapped to red, green, blue");
+    eprintln!("  --orbits N         number of random points for --buddhabrot/--nebulabrot");
+    eprintln!("                     (default 20 per pixel)");
+    eprintln!("  --frames N         render a zoom animation of N frames to numbered files");
+    eprintln!("                     (FILE zoom.png becomes zoom0000.png, zoom0001.png, ...)");
+    eprintln!("  --end-center RE,IM center of the last frame (default: the start center)");
+    eprintln!("  --zoom-factor Z    total magnification from the first to the last frame");
+    eprintln!("  --strip-rows N     render N rows at a time and stream them into the PNG file,");
+    eprintln!("                     so memory grows with the strip instead of the whole image");
+    eprintln!("  --checkpoint DIR   render in tiles saved to DIR as they finish; DIR is removed");
+    eprintln!("                     after the image is written");
+    eprintln!("  --tile-size N      tile size for --checkpoint and serve (default 256)");
+    eprintln!("  --resume           continue the render saved in the --checkpoint directory");
+    eprintln!("  --sixel            view: draw with sixel graphics instead of colored half blocks");
+    eprintln!("  --port N           serve: port to listen on (default 8080)");
+    eprintln!("  --threads N        number of render threads (default: number of CPUs)");
+    eprintln!("  --stats            print per-thread render time");
+    eprintln!("Exit status: 2 invalid arguments, 3 file read/write error, 4 image encoding error,");
+    eprintln!("5 the --checkpoint directory cannot be used for this render, 6 the serve CACHEDIR holds");
+    eprintln!("tiles of a different render");
+}
+
+/// 引数のパース
+/// --で始まる引数はオプションとして扱い、残りを位置引数として扱う
+/// argsはプログラム名を含むコマンドライン全体
+/// 誤りはどの引数の何が悪かったかをMandelError::Argumentで返す
+fn parse_args(mut args: Vec<String>) -> Result<Arguments, MandelError> {
+    let mut command = match args.get(1).map(String::as_str) {
+        Some("view") => {
+            args.remove(1);
+            Command::View(Graphics::HalfBlock)
+        }
+        Some("serve") => {
+            args.remove(1);
+            Command::Serve(8080)
+        }
+        _ => Command::Render,
+    };
+
+    // --fromで指定された画像に記録された指定を、コマンドラインの前に置いたオプションとして扱う
+    // 後に書いたオプションが優先されるので、コマンドラインで上書きできる
+    let from_text = match args.iter().position(|arg| arg == "--from") {
+        Some(index) if index + 1 < args.len() => {
+            let text = read_text(&args[index + 1])?;
+            let options = text_options(&text);
+            args.splice(1..1, options);
+            Some(text)
+        }
+        _ => None,
+    };
+
+    let mut positional = Vec::new();
+    let mut palette = Palette::Gray;
+    let mut alpha = false;
+    let mut smooth = false;
+    let mut limit = 255;
+    let mut depth = None;
+    let mut julia = None;
+    let mut formula = Formula::Mandelbrot;
+    let mut threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut stats = false;
+    let mut samples = 1;
+    let mut jitter = false;
+    let mut deep = false;
+    let mut frames = None;
+    let mut end_center = None;
+    let mut zoom: f64 = 1.0;
+    let mut shortcuts = true;
+    let mut kernel = Kernel::Scalar;
+    let mut distance = false;
+    let mut equalize = false;
+    let mut trap = None;
+    let mut newton = None;
+    let mut buddhabrot = false;
+    let mut nebulabrot = None;
+    let mut orbits = None;
+    let mut strip_rows = None;
+    let mut checkpoint_dir = None;
+    let mut tile_size = 256;
+    let mut resume = false;
+    let mut center = None;
+    let mut view_zoom = None;
+    let mut view_width = None;
+    let mut rotation = 0.0;
+
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        // 値を取るオプションは次の引数を値として読む
+        let mut value = || iter.next().cloned().ok_or_else(|| MandelError::argument(arg, "missing value"));
+        match arg.as_str() {
+            "--palette" => palette = parse_value(arg, "a palette name or gradient stops", &value()?)?,
+            "--gradient" => palette = Palette::Gradient(parse_value(arg, "gradient stops POS:RRGGBB,...", &value()?)?),
+            "--alpha" => alpha = true,
+            "--smooth" => smooth = true,
+            "--max-iter" => limit = parse_value(arg, "an iteration count", &value()?)?,
+            "--depth" => depth = Some(value()?),
+            "--julia" => julia = Some(complex_value(arg, &value()?)?),
+            "--formula" => formula = parse_value(arg, "a formula name", &value()?)?,
+            "--threads" => threads = parse_value::<usize>(arg, "a thread count", &value()?)?.max(1),
+            "--stats" => stats = true,
+            "--sixel" => match command {
+                Command::View(_) => command = Command::View(Graphics::Sixel),
+                _ => return Err(MandelError::argument(arg, "only applies to view")),
+            },
+            "--port" => match command {
+                Command::Serve(_) => command = Command::Serve(parse_value(arg, "a port number", &value()?)?),
+                _ => return Err(MandelError::argument(arg, "only applies to serve")),
+            },
+            "--supersample" => samples = parse_value::<usize>(arg, "a sample count", &value()?)?.max(1),
+            "--jitter" => jitter = true,
+            "--deep" => deep = true,
+            "--no-shortcuts" => shortcuts = false,
+            "--distance" => distance = true,
+            "--equalize" => equalize = true,
+            "--trap" => trap = Some(parse_value(arg, "a trap shape such as point:RE,IM or circle:RE,IM:R", &value()?)?),
+            "--newton" => newton = Some(parse_value(arg, "polynomial coefficients such as 1:0:0:-1", &value()?)?),
+            "--buddhabrot" => buddhabrot = true,
+            "--nebulabrot" => {
+                let value = value()?;
+                let limits: Option<Vec<usize>> = value.split(',').map(|limit| limit.parse().ok()).collect();
+                nebulabrot = match limits {
+                    Some(limits) if limits.len() == 3 => Some(limits),
+                    _ => return Err(invalid(arg, "three iteration limits R,G,B", &value)),
+                };
+            }
+            "--orbits" => orbits = Some(parse_value(arg, "a number of points", &value()?)?),
+            "--from" => {
+                value()?;
+            }
+            "--checkpoint" => checkpoint_dir = Some(value()?),
+            "--tile-size" => tile_size = parse_value::<usize>(arg, "a tile size in pixels", &value()?)?.max(1),
+            "--resume" => resume = true,
+            "--strip-rows" => strip_rows = Some(parse_value::<usize>(arg, "a row count", &value()?)?.max(1)),
+            "--kernel" => kernel = match value()?.as_str() {
+                "scalar" => Kernel::Scalar,
+                "simd" => Kernel::Simd,
+                other => return Err(invalid(arg, "scalar or simd", other)),
+            },
+            "--frames" => frames = match parse_value(arg, "a frame count", &value()?)? {
+                0 => return Err(MandelError::argument(arg, "needs at least 1 frame")),
+                frames => Some(frames),
+            },
+            "--end-center" => end_center = Some(complex_value(arg, &value()?)?),
+            "--zoom-factor" => zoom = parse_value(arg, "a number", &value()?)?,
+            "--center" => center = Some(value()?),
+            "--zoom" => view_zoom = Some(parse_value::<f64>(arg, "a number", &value()?)?),
+            "--width" => view_width = Some(parse_value(arg, "a number", &value()?)?),
+            "--rotate" => rotation = parse_value::<f64>(arg, "an angle in degrees", &value()?)?.to_radians(),
+            _ if arg.starts_with("--") => return Err(MandelError::argument(arg, "unknown option")),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    // viewとserveでは全ての位置引数を省略できる
+    let viewing = matches!(command, Command::View(_));
+    let serving = matches!(command, Command::Serve(_));
+    if viewing && positional.is_empty() {
+        positional.push(String::from("view.png"));
+    }
+    // serveの位置引数はタイルを保存するディレクトリだけで、画像の大きさはタイルの大きさとする
+    if serving {
+        if positional.len() > 1 {
+            return Err(MandelError::argument("CACHEDIR", format!("expected 1 argument, got {}", positional.len())));
+        }
+        if positional.is_empty() {
+            positional.push(String::from("tiles"));
+        }
+        positional.push(format!("{}x{}", tile_size, tile_size));
     }
 
-    let bounds = parse_pair(&args[2], 'x').expect("error parsing image dimensions");
-    let upper_left = parse_complex(&args[3]).expect("error parsing upper left corner point");
-    let lower_right = parse_complex(&args[4]).expect("error parsing lower right corner point");
+    // --fromでは、省略された画像の大きさと角の座標を記録から補う
+    if let Some(text) = &from_text {
+        let recorded = |key: &str| {
+            let key = format!("{}{}", TEXT_PREFIX, key);
+            text.iter().find(|(k, _)| *k == key)
+                .map(|(_, value)| value.clone())
+                .ok_or_else(|| MandelError::argument("--from", format!("the image does not record {}", key)))
+        };
+        if positional.len() == 1 {
+            positional.push(recorded("pixels")?);
+        }
+        if center.is_none() && positional.len() == 2 {
+            positional.push(recorded("upper-left")?);
+            positional.push(recorded("lower-right")?);
+        }
+    }
 
-    let mut pixels = vec![0; bounds.0 * bounds.1];
+    if viewing && positional.len() == 1 {
+        positional.push(String::from("1280x720"));
+    }
+    if (viewing || serving) && center.is_none() && positional.len() == 2 {
+        center = Some(String::from("-0.5,0"));
+    }
 
-    // 並列化されていないバージョン
-    // render(&mut pixels, bounds, upper_left, lower_right);
-
-    // crossbeamクレートによる並列化
-    let threads = 8;
-    let rows_per_band = bounds.1 / threads + 1;
-
-    {
-        let bands: Vec<&mut [u8]> = pixels.chunks_mut(rows_per_band * bounds.0).collect();
-
-        // クロージャ
-        // crossbeam::scopeは、全ての生成されたスレッドが終了するのを待ってから終了する
-        // プログラマにとっては、corssbeam::scopeがリターンしてきたら、画像の計算が終了していることが保証される
-        crossbeam::scope(|spawner| {
-            // クロージャないで新しいスレッドを生成する
-            for (i, band) in bands.into_iter().enumerate() {
-                let top = rows_per_band * i;
-                let height = band.len() / bounds.0;
-                let band_bounds = (bounds.0, height);
-                let band_upper_left = pixel_to_point(bounds, (0, top), upper_left, lower_right);
-                let band_lower_right = pixel_to_point(bounds, (bounds.0, top + height), upper_left, lower_right);
-
-                spawner.spawn(move |_| {
-                    render(band, band_bounds, band_upper_left, band_lower_right);
-                });
+    // --centerで範囲を指定する場合、位置引数は角の座標を含まない
+    let names: &[&str] = if center.is_some() { &["FILE", "PIXELS"] } else { &["FILE", "PIXELS", "UPPERLEFT", "LOWERRIGHT"] };
+    if positional.len() != names.len() {
+        return Err(MandelError::argument(&names.join(" "),
+                                         format!("expected {} arguments, got {}", names.len(), positional.len())));
+    }
+    if center.is_none() && (view_zoom.is_some() || view_width.is_some()) {
+        return Err(MandelError::argument("--zoom/--width", "requires --center"));
+    }
+    let width = match (view_zoom, view_width) {
+        (Some(_), Some(_)) => return Err(MandelError::argument("--zoom/--width", "only one of them can be given")),
+        (Some(zoom), None) => 4.0 / zoom,
+        (None, Some(width)) => width,
+        (None, None) => 4.0,
+    };
+    if !(width > 0.0 && width.is_finite()) {
+        return Err(MandelError::argument("--zoom/--width", "must be positive"));
+    }
+
+    // 繰り返し回数を保存する形式では、深さを指定しなければ回数をそのまま書き出す
+    let output = OutputFormat::from_filename(&positional[0]);
+    let depth = depth.unwrap_or_else(|| String::from(if output.prefers_counts() { "float" } else { "8" }));
+
+    // グレースケールのパレットで透過も不要なら、従来どおり8ビットグレースケールで出力する
+    // Nebulabrotはパレットを使わずRGBで出力する
+    let format = match (depth.as_str(), &palette, alpha) {
+        ("8", _, true) => PixelFormat::Rgba8,
+        ("8", Palette::Gray, false) if nebulabrot.is_none() => PixelFormat::Gray8,
+        ("8", _, false) => PixelFormat::Rgb8,
+        ("16", Palette::Gray, false) => PixelFormat::Gray16,
+        ("float", _, _) => PixelFormat::IterF32,
+        _ => return Err(invalid("--depth", "8, 16 or float (16-bit output is grayscale only)", &depth)),
+    };
+
+    if format == PixelFormat::IterF32 && !output.supports(format) {
+        return Err(MandelError::argument("--depth", "float iteration counts need a .raw, .npy or .tif FILE"));
+    }
+    if !output.supports(format) {
+        return Err(MandelError::argument("FILE", format!("{:?} output cannot store {:?} pixels", output, format)));
+    }
+
+    if format == PixelFormat::IterF32 && samples > 1 {
+        return Err(MandelError::argument("--supersample", "cannot be combined with --depth float"));
+    }
+
+    if deep && (julia.is_some() || formula != Formula::Mandelbrot) {
+        return Err(MandelError::argument("--deep", "supports only the mandelbrot formula"));
+    }
+    if distance && (deep || formula.derivative(Complex {re: 0.0, im: 0.0}).is_none()) {
+        return Err(MandelError::argument("--distance", "cannot be combined with --deep, burning-ship or tricorn"));
+    }
+    if equalize && (distance || samples > 1 || format == PixelFormat::IterF32) {
+        return Err(MandelError::argument("--equalize", "cannot be combined with --distance, --supersample or --depth float"));
+    }
+    if trap.is_some() && (deep || distance || equalize || smooth) {
+        return Err(MandelError::argument("--trap", "cannot be combined with --deep, --distance, --equalize or --smooth"));
+    }
+    if newton.is_some() && (deep || julia.is_some() || formula != Formula::Mandelbrot || distance || equalize || smooth || trap.is_some()) {
+        return Err(MandelError::argument("--newton", "cannot be combined with --deep, --julia, --formula, --distance, --equalize, --smooth or --trap"));
+    }
+    if nebulabrot.is_some() && !matches!(format, PixelFormat::Rgb8 | PixelFormat::Rgba8) {
+        return Err(MandelError::argument("--nebulabrot", "writes 8-bit RGB only"));
+    }
+    let buddhabrot = buddhabrot || nebulabrot.is_some();
+    if !buddhabrot && orbits.is_some() {
+        return Err(MandelError::argument("--orbits", "requires --buddhabrot or --nebulabrot"));
+    }
+    if buddhabrot && (deep || julia.is_some() || distance || equalize || samples > 1 || smooth || trap.is_some()
+        || newton.is_some()) {
+        return Err(MandelError::argument("--buddhabrot/--nebulabrot",
+                                         "cannot be combined with --deep, --julia, --distance, --equalize, --supersample, --smooth, --trap or --newton"));
+    }
+    let bounds = parse_pair(&positional[1], 'x').ok_or_else(|| invalid("PIXELS", "WIDTHxHEIGHT", &positional[1]))?;
+    if bounds.0 == 0 || bounds.1 == 0 {
+        return Err(MandelError::argument("PIXELS", "width and height must be at least 1"));
+    }
+    let (view, deep) = match &center {
+        // 深い拡大モードでは中心を10進数のまま参照点とし、範囲は中心からのずれとする
+        Some(center) if deep => {
+            let center = parse_pair(center, ',').ok_or_else(|| invalid("--center", "RE,IM", center))?;
+            (Viewport::centered(bounds, Complex { re: 0.0, im: 0.0 }, width), Some(DeepView::Center(center)))
+        }
+        Some(center) => {
+            let center = complex_value("--center", center)?;
+            (Viewport::centered(bounds, center, width), None)
+        }
+        None => {
+            let view = Viewport {
+                bounds,
+                upper_left: complex_value("UPPERLEFT", &positional[2])?,
+                lower_right: complex_value("LOWERRIGHT", &positional[3])?,
+            };
+            let decimal = |name: &str, value: &str| {
+                parse_pair(value, ',').ok_or_else(|| invalid(name, "RE,IM in decimal notation", value))
+            };
+            let deep = if deep {
+                Some(DeepView::Corners(decimal("UPPERLEFT", &positional[2])?, decimal("LOWERRIGHT", &positional[3])?))
+            } else {
+                None
+            };
+            // 深い拡大モードではf64に丸めた角の幅や高さが0になることがあるので、10進数の角の中心からのずれで比べる
+            // viewとserveは角の座標から中心と幅だけを使うので、縦横比は問題にならない
+            let aspect = match &deep {
+                Some(DeepView::Corners(ul, lr)) => {
+                    let (upper_left, lower_right) = deep::corner_offsets(ul, lr);
+                    Viewport { bounds, upper_left, lower_right }.pixel_aspect()
+                }
+                _ => view.pixel_aspect(),
+            };
+            if !(viewing || serving) && (aspect.abs() - 1.0).abs() > 0.01 {
+                eprintln!("warning: the corners give non-square pixels (width/height {:.3}), the image will look stretched;",
+                          aspect.abs());
+                eprintln!("         use --center with --zoom or --width to keep the aspect ratio");
             }
-        }).unwrap();
+            (view, deep)
+        }
+    };
+    let (upper_left, lower_right) = (view.upper_left, view.lower_right);
+
+    if deep.is_some() && frames.is_some() {
+        return Err(MandelError::argument("--frames", "cannot be combined with --deep"));
+    }
+    if frames.is_none() && (end_center.is_some() || zoom != 1.0) {
+        return Err(MandelError::argument("--end-center/--zoom-factor", "requires --frames"));
+    }
+    if !(zoom > 0.0 && zoom.is_finite()) {
+        return Err(MandelError::argument("--zoom-factor", "must be positive"));
+    }
+    if !rotation.is_finite() {
+        return Err(MandelError::argument("--rotate", "must be a finite angle"));
+    }
+    if strip_rows.is_some() && output != OutputFormat::Png {
+        return Err(MandelError::argument("--strip-rows", "writes PNG only"));
+    }
+    if strip_rows.is_some() && (equalize || frames.is_some()) {
+        return Err(MandelError::argument("--strip-rows", "cannot be combined with --equalize or --frames"));
     }
 
-    write_image(&args[1], &pixels, bounds).expect("error writing PNG file");
+    if resume && checkpoint_dir.is_none() {
+        return Err(MandelError::argument("--resume", "requires --checkpoint"));
+    }
+    if checkpoint_dir.is_some() && (equalize || frames.is_some() || strip_rows.is_some()) {
+        return Err(MandelError::argument("--checkpoint", "cannot be combined with --equalize, --frames or --strip-rows"));
+    }
+    if viewing && (deep.is_some() || frames.is_some() || strip_rows.is_some() || checkpoint_dir.is_some()) {
+        return Err(MandelError::argument("view", "cannot be combined with --deep, --frames, --strip-rows or --checkpoint"));
+    }
+    if viewing && format == PixelFormat::IterF32 {
+        return Err(MandelError::argument("view", "cannot save iteration counts (--depth float, .raw or .npy)"));
+    }
+    // タイルは1枚ずつ描くので、回転や平坦化をするとタイルの境目で絵がずれる
+    if serving && (deep.is_some() || frames.is_some() || strip_rows.is_some() || checkpoint_dir.is_some()) {
+        return Err(MandelError::argument("serve", "cannot be combined with --deep, --frames, --strip-rows or --checkpoint"));
+    }
+    if serving && (rotation != 0.0 || equalize || format == PixelFormat::IterF32) {
+        return Err(MandelError::argument("serve", "cannot be combined with --rotate, --equalize or --depth float"));
+    }
+    if buddhabrot && (viewing || serving || frames.is_some() || strip_rows.is_some() || checkpoint_dir.is_some()) {
+        return Err(MandelError::argument("--buddhabrot/--nebulabrot",
+                                         "cannot be combined with view, serve, --frames, --strip-rows or --checkpoint"));
+    }
+    let buddhabrot = buddhabrot.then(|| Buddhabrot {
+        limits: nebulabrot.unwrap_or_else(|| vec![limit]),
+        samples: orbits.unwrap_or(bounds.0 * bounds.1 * 20),
+    });
+    let key = render_key(&args[1..]);
+    let checkpoint = checkpoint_dir.map(|dir| Checkpoint {
+        dir: PathBuf::from(dir),
+        tile_size,
+        key: key.clone(),
+        resume,
+    });
+
+    let animation = frames.map(|frames| Animation {
+        frames,
+        end_center: end_center.unwrap_or((upper_left + lower_right) / 2.0),
+        zoom,
+    });
+
+    Ok(Arguments {
+        command,
+        filename: positional[0].clone(),
+        bounds,
+        upper_left,
+        lower_right,
+        params: RenderParams {
+            palette, format, limit, smooth, julia, formula, samples, jitter, reference: None, shortcuts, kernel,
+            distance, equalize, threads, rotation, trap, newton: newton.map(Newton::new),
+        },
+        stats,
+        deep,
+        animation,
+        buddhabrot,
+        strip_rows,
+        checkpoint,
+        key,
+    })
+}
+
+/// optionの値valueが読めなかった時のエラー expectedは期待した形の説明
+fn invalid(option: &str, expected: &str, value: &str) -> MandelError {
+    MandelError::argument(option, format!("expected {}, got {:?}", expected, value))
 }
 
-/// 大きさがbounds で指定されたバッファpixelsをfilenameで指定されたファイルに書き出す
-fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize)) -> Result<(), std::io::Error> {
-    // ファイルオープンし、画像をそのファイルに書き出す
-    let output = match File::create(filename) {
-        Ok(f) => f,
-        Err(e) => {
-            return Err(e);
+/// オプションの値をFromStrでパースする
+fn parse_value<T: FromStr>(option: &str, expected: &str, value: &str) -> Result<T, MandelError> {
+    T::from_str(value).map_err(|_| invalid(option, expected, value))
+}
+
+/// "RE,IM"の形の複素数をパースする
+fn complex_value(option: &str, value: &str) -> Result<Complex<f64>, MandelError> {
+    parse_complex(value).ok_or_else(|| invalid(option, "RE,IM", value))
+}
+
+/// 描画の指定をtEXtチャンクの(キー, 値)にする
+/// paramsで表せる指定はoutput::render_textで記録し、深い拡大モードとブッダブロの指定を加える
+/// upper_left lower_rightは画像の角 (深い拡大モードではargs.deepから10進数のまま求める)
+fn render_text(args: &Arguments, upper_left: Complex<f64>, lower_right: Complex<f64>) -> Vec<(String, String)> {
+    let corner = |p: Complex<f64>| format!("{},{}", p.re, p.im);
+    let (upper_left, lower_right) = match &args.deep {
+        None => (corner(upper_left), corner(lower_right)),
+        Some(DeepView::Corners(ul, lr)) => (format!("{},{}", ul.0, ul.1), format!("{},{}", lr.0, lr.1)),
+        Some(DeepView::Center(center)) => {
+            // 角は中心からのずれとして持っているので、10進数に直して中心に足す
+            let offset = |p: Complex<f64>| {
+                let decimal = |x: f64| Decimal::from_str(&format!("{:e}", x)).expect("formatting offset");
+                format!("{},{}", &center.0 + &decimal(p.re), &center.1 + &decimal(p.im))
+            };
+            (offset(args.upper_left), offset(args.lower_right))
         }
     };
-    let encoder = PNGEncoder::new(output);
 
-    encoder.encode(pixels, bounds.0 as u32, bounds.1 as u32, ColorType::Gray(8));
-
-    Ok(())
+    let mut text = output::render_text(&args.params, args.bounds, upper_left, lower_right);
+    let mut push = |key: &str, value: String| text.push((format!("{}{}", TEXT_PREFIX, key), value));
+    if args.deep.is_some() {
+        push("deep", "true".to_string());
+    }
+    if let Some(buddhabrot) = &args.buddhabrot {
+        match buddhabrot.limits.as_slice() {
+            [r, g, b] => push("nebulabrot", format!("{},{},{}", r, g, b)),
+            _ => push("buddhabrot", "true".to_string()),
+        }
+        push("orbits", buddhabrot.samples.to_string());
+    }
+    text
 }
 
+/// render_textで記録した指定をコマンドラインのオプションに戻す
+/// 値が"true"のものは値を取らないオプションとする 画像の大きさと角の座標は位置引数なので含めない
+fn text_options(text: &[(String, String)]) -> Vec<String> {
+    let mut options = Vec::new();
+    for (key, value) in text {
+        let name = match key.strip_prefix(TEXT_PREFIX) {
+            Some("pixels" | "upper-left" | "lower-right") | None => continue,
+            Some(name) => name,
+        };
+        options.push(format!("--{}", name));
+        if value != "true" {
+            options.push(value.clone());
+        }
+    }
+    options
+}
 
-/// sが適切な形であればSome<(x,y)>を返す　そうでなければNone
-/// <T: FromStr> は FromStrトレイトを実装する任意の型Tに対して　と読む
-/// Option<(T, T)> NoneかSome((v1, v2))の値となる。 (v1, v2)は型Tの値2つのタプル
-fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
-
-    /// 文字列の中からseparatorに合致する文字を探す。
-    /// findがNoneを返す場合は、セパレータ文字が文字列には現れなかったことを意味し、Noneを返し、パース失敗を表す
-    match s.find(separator) {
-        None => None,
-        Some(index) => {
-            // indexはseparator文字の位置を表す
-            // separatorの文字の前後を取り出した文字列のスライスをとり、型Tのタプルを作る
-            // これに対してマッチングを行う
-            // _は何にでもマッチし、その値を無視する
-            match (T::from_str(&s[..index]), T::from_str(&s[index +1..])) {
-                (Ok(l), Ok(r)) => Some((l, r)), // 双方のパースが成功した場合
-                _ => None
+/// 描画の結果に関わる引数をつなげて、チェックポイントやタイルの照合に使う文字列にする
+/// スレッド数や統計の表示、再開の指定、ポート番号は結果を変えないので除く
+fn render_key(args: &[String]) -> String {
+    let mut key = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--threads" | "--checkpoint" | "--port" => {
+                iter.next();
             }
+            "--stats" | "--resume" => {}
+            _ => key.push(arg.as_str()),
         }
     }
+    key.join(" ")
 }
 
-/// カンマで分けられたfloatのペアをパースして複素数を返す
-fn parse_complex(s: &str) -> Option<Complex<f64>> {
-    match parse_pair(s, ',') {
-        Some((re, im)) => Some(Complex {re, im}),
-        None => None
+/// エラーはメッセージを表示し、種類ごとに決まった終了コードで終了する
+/// 引数の誤りの場合は使い方も表示する
+fn main() {
+    if let Err(e) = run() {
+        if let MandelError::Argument { .. } = e {
+            print_usage(&env::args().next().unwrap_or_else(|| String::from("mandelbrot")));
+        }
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
     }
 }
 
-///
-///limitを繰り返し回数の上限として、cがマンデルブロ集合に含まれるかを判定する
-///
-/// cがマンデルブロ集合に含まれないならSome(i)を返す
-/// iはcが原点を中心とする半径2の縁から出るまでにかかった繰り返し回数となる
-/// cがマンデルブロ集合に含まれているらしい(繰り返し上限に達しても、cがマンデルブロ集合に含まれないことを示せなかった場合)
-/// Noneを返す
-/// 
-/// 戻り値はOption<usize>
-fn escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
-    let mut z = Complex {re: 0.0, im: 0.0};
-    for i in 0..limit {
+fn run() -> Result<(), MandelError> {
+    let mut args = parse_args(env::args().collect())?;
+    let bounds = args.bounds;
+    let channels = args.params.format.channels();
+
+    if let Command::View(graphics) = args.command {
+        let view = View {
+            center: (args.upper_left + args.lower_right) / 2.0,
+            width: args.lower_right.re - args.upper_left.re,
+        };
+        let mut viewer = Viewer { view, params: args.params, graphics, save_filename: args.filename, save_bounds: bounds };
+        return viewer.run();
+    }
+
+    if let Command::Serve(port) = args.command {
+        let map = TileMap {
+            center: (args.upper_left + args.lower_right) / 2.0,
+            width: args.lower_right.re - args.upper_left.re,
+            tile_size: bounds.0,
+        };
+        let server = TileServer { map, params: args.params, cache: PathBuf::from(args.filename), key: args.key, verbose: args.stats };
+        return server.run(&format!("127.0.0.1:{}", port));
+    }
+
+    if let Some(animation) = &args.animation {
+        return render_animation(&args, animation);
+    }
 
-        // 半径2の円からでたかどうか
-        // zの原点からの距離の2乗
-        if z.norm_sqr() > 4.0 {
-            return Some(i);
+    // 深い拡大モードでは、画像の中心を参照点として、角の座標を参照点からのずれに置き換える
+    let (upper_left, lower_right) = match &args.deep {
+        Some(view) => {
+            let (reference, upper_left, lower_right) = match view {
+                DeepView::Corners(upper_left, lower_right) => {
+                    deep::centered_view(upper_left, lower_right, args.params.limit)
+                }
+                DeepView::Center(center) => {
+                    let width = args.lower_right.re - args.upper_left.re;
+                    (deep::centered_at(center, width, args.params.limit), args.upper_left, args.lower_right)
+                }
+            };
+            if args.stats {
+                eprintln!("reference orbit: {} iterations", reference.len() - 1);
+            }
+            args.params.reference = Some(reference);
+            (upper_left, lower_right)
         }
-        z = z * z + c;
+        None => (args.upper_left, args.lower_right),
+    };
+
+    if let Some(strip_rows) = args.strip_rows {
+        let start = Instant::now();
+        let text = render_text(&args, upper_left, lower_right);
+        let mut png = PngStream::create(&args.filename, bounds, args.params.format, &text)?;
+        let thread_stats = render_strips(bounds, upper_left, lower_right, &args.params, strip_rows, |rows| png.write_rows(rows))?;
+        png.finish()?;
+        if args.stats {
+            print_stats(&thread_stats, &args.params, bounds, start.elapsed());
+        }
+        return Ok(());
     }
-    None
-}
-
-/// 出力される画像のピクセルの位置をとり、対応する複素平面上の点を返す
-/// pixelは画像上の特定のピクセルを（行,列)ペアの形で指定する
-/// 仮引数upper_left lower_rightは出力画像に描画する複素平面を左上と右下で指定する
-fn pixel_to_point(bounds: (usize, usize),
-                    pixel: (usize, usize),
-                    upper_left: Complex<f64>,
-                    lower_right: Complex<f64>) -> Complex<f64> {
-    let (width, height) = (lower_right.re - upper_left.re, upper_left.im - lower_right.im);
-
-    // imが引き算となっている理由。 上に動くとpixel.1は増えるが、虚部は小さくなるため
-    // pixel.0 pixel.1はタプルの要素を参照
-    Complex {
-        re: upper_left.re + pixel.0 as f64 * width / bounds.0 as f64,
-        im: upper_left.im - pixel.1 as f64 * height / bounds.1 as f64
-    }
-}
-
-fn render(pixels: &mut [u8],
-            bounds: (usize, usize),
-            upper_left: Complex<f64>,
-            lower_right: Complex<f64>,
-) {
-    assert!(pixels.len() == bounds.0 * bounds.1);
-
-    for row in 0..bounds.1 {
-        for column in 0..bounds.0 {
-            let point = pixel_to_point(bounds, (column, row),
-                upper_left, lower_right);
-            pixels[row * bounds.0 + column] = 
-                match escape_time(point, 255) {
-                    None => 0,
-                    Some(count) => 255 - count as u8
-                };
+
+    let mut pixels = vec![0; bounds.0 * bounds.1 * channels];
+
+    // 並列化されていないバージョン
+    // render(&mut pixels, bounds, upper_left, lower_right, &args.params);
+
+    let start = Instant::now();
+    if let Some(buddhabrot) = &args.buddhabrot {
+        let orbit_stats = buddhabrot.render(&mut pixels, bounds, upper_left, lower_right, &args.params);
+        if args.stats {
+            print_orbit_stats(&orbit_stats, start.elapsed());
+        }
+    } else {
+        let thread_stats = match &args.checkpoint {
+            Some(checkpoint) => {
+                let (thread_stats, skipped) = checkpoint.render(&mut pixels, bounds, upper_left, lower_right, &args.params)?;
+                if args.stats {
+                    eprintln!("resumed {} saved tiles", skipped);
+                }
+                thread_stats
+            }
+            None => render_image(&mut pixels, bounds, upper_left, lower_right, &args.params),
+        };
+        if args.stats {
+            print_stats(&thread_stats, &args.params, bounds, start.elapsed());
+        }
+    }
+
+    let text = render_text(&args, upper_left, lower_right);
+    write_image_with_text(&args.filename, &pixels, bounds, args.params.format, &text)?;
+
+    // 画像を書き出せたので、タイルはもう要らない
+    if let Some(checkpoint) = &args.checkpoint {
+        if let Err(e) = checkpoint::remove(&checkpoint.dir) {
+            eprintln!("warning: could not remove the checkpoint: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// アニメーションの各フレームを描画して連番のファイルに書き出す
+/// 描画のスレッドとバッファは全フレームで使い回す (render_sequence)
+fn render_animation(args: &Arguments, animation: &Animation) -> Result<(), MandelError> {
+    let bounds = args.bounds;
+    let views: Vec<_> = (0..animation.frames)
+        .map(|frame| animation.frame_view(args.upper_left, args.lower_right, frame))
+        .collect();
+
+    let mut start = Instant::now();
+    render_sequence(bounds, views.iter().copied(), &args.params, |frame, pixels, thread_stats| {
+        let filename = animation::frame_filename(&args.filename, frame);
+        if args.stats {
+            eprintln!("frame {}: {}", frame, filename);
+            print_stats(thread_stats, &args.params, bounds, start.elapsed());
         }
+        let (upper_left, lower_right) = views[frame];
+        let text = render_text(args, upper_left, lower_right);
+        write_image_with_text(&filename, pixels, bounds, args.params.format, &text)?;
+        start = Instant::now();
+        Ok(())
+    })
+}
+
+/// render_parallelの結果の記録を表示する
+/// elapsedは画像全体の描画にかかった時間
+fn print_stats(thread_stats: &[ThreadStats], params: &RenderParams, bounds: (usize, usize), elapsed: Duration) {
+    let mut total = PixelStats::default();
+    for (i, stat) in thread_stats.iter().enumerate() {
+        eprintln!("thread {}: {} rows in {:.3}s", i, stat.rows, stat.busy.as_secs_f64());
+        total.add(&stat.pixels);
+    }
+    let megapixels = (bounds.0 * bounds.1) as f64 / 1e6;
+    eprintln!("{:?} kernel: {:.3}s, {:.2} Mpixels/s", params.kernel, elapsed.as_secs_f64(),
+              megapixels / elapsed.as_secs_f64());
+    if params.reference.is_some() {
+        eprintln!("rebased pixels: {}", total.rebased);
+    } else if params.shortcuts {
+        eprintln!("short-circuited points: {} by cardioid/bulb test, {} by periodicity",
+                  total.cardioid, total.periodic);
+    }
+}
+
+/// Buddhabrot::renderの結果の記録を表示する
+fn print_orbit_stats(orbit_stats: &[OrbitStats], elapsed: Duration) {
+    let mut samples = 0;
+    for (i, stat) in orbit_stats.iter().enumerate() {
+        eprintln!("thread {}: {} points ({} escaped) in {:.3}s", i, stat.samples, stat.escaped, stat.busy.as_secs_f64());
+        samples += stat.samples;
     }
+    eprintln!("buddhabrot: {:.3}s, {:.2} Mpoints/s", elapsed.as_secs_f64(), samples as f64 / 1e6 / elapsed.as_secs_f64());
 }
 
 /**
  * xの値に応じて、xは0に近づくか、1のままか、無限大に近づくかのいずれか
  */
+#[allow(dead_code)]
 fn square_loop(mut x: f64) {
     // loop
     loop {
@@ -171,6 +766,7 @@ fn square_loop(mut x: f64) {
     }
 }
 
+#[allow(dead_code)]
 fn square_add_loop(c: f64) {
     let mut x = 0.;
     loop {
@@ -181,6 +777,7 @@ fn square_add_loop(c: f64) {
 /**
  * 複素数対応版ループ
  */
+#[allow(dead_code)]
 fn complex_square_add_loop(c: Complex<f64>) {
     let mut z = Complex { re: 0.0, im: 0.0 };
     loop {
@@ -189,26 +786,52 @@ fn complex_square_add_loop(c: Complex<f64>) {
 }
 
 #[test]
-fn test_parse_pair() {
-    assert_eq!(parse_pair::<i32>("", ','), None);
-    assert_eq!(parse_pair::<i32>("10", ','), None);
-    assert_eq!(parse_pair::<i32>(",10", ','), None);
-    assert_eq!(parse_pair::<i32>("10,20", ','), Some((10, 20)));
-    assert_eq!(parse_pair::<i32>("10,20xy", ','), None);
-    assert_eq!(parse_pair::<f64>("0.5x", 'x'), None);
-    assert_eq!(parse_pair::<f64>("0.5x1.5", 'x'), Some((0.5, 1.5)));
-}
+fn test_from_reproduces_render_options() {
+    let program = String::from("mandelbrot");
+    let filename = env::temp_dir().join(format!("mandelbrot-from-test-{}.png", std::process::id()));
+    let filename = filename.to_str().unwrap().to_string();
+    let command_lines: [&[&str]; 4] = [
+        &["--distance", "--supersample", "3", "--jitter", "--no-shortcuts", "--alpha", "--palette", "fire"],
+        &["--equalize", "--depth", "16", "--smooth", "--julia", "-0.8,0.156"],
+        &["--trap", "circle:0,0:0.5", "--rotate", "30", "--formula", "tricorn", "--max-iter", "1000"],
+        &["--newton", "1:0:0:-1", "--palette", "hsv", "--supersample", "2"],
+    ];
+    for options in command_lines {
+        let mut args = vec![program.clone()];
+        args.extend(options.iter().map(|s| s.to_string()));
+        args.extend(["out.png", "40x30", "-2,1.2", "1,-1.2"].map(String::from));
+        let original = parse_args(args).unwrap();
 
-#[test]
-fn test_parse_complex() {
-    assert_eq!(parse_complex("1.25,-0.0625"), Some(Complex {re: 1.25, im: -0.0625}));
-    assert_eq!(parse_complex(", -0.0625"), None);
+        let text = render_text(&original, original.upper_left, original.lower_right);
+        let pixels = vec![0; original.bounds.0 * original.bounds.1 * original.params.format.channels()];
+        write_image_with_text(&filename, &pixels, original.bounds, original.params.format, &text).unwrap();
+        let from = parse_args(vec![program.clone(), "--from".to_string(), filename.clone(), "out.png".to_string()]).unwrap();
+
+        assert_eq!(format!("{:?}", from.params), format!("{:?}", original.params), "{:?}", options);
+        assert_eq!((from.bounds, from.upper_left, from.lower_right),
+                   (original.bounds, original.upper_left, original.lower_right));
+    }
+    std::fs::remove_file(&filename).unwrap();
 }
 
 #[test]
-fn test_pixel_to_point() {
-    assert_eq!(pixel_to_point((100, 200), (25, 175),
-                                Complex {re: -1.0, im: 1.0},
-                                Complex {re: 1.0, im: -1.0}),
-                                Complex {re: -0.5, im: -0.75});
-}
\ No newline at end of file
+fn test_rejects_non_finite_numbers() {
+    let parse = |options: &[&str]| {
+        let mut args = vec![String::from("mandelbrot")];
+        args.extend(options.iter().map(|s| s.to_string()));
+        args.extend(["out.png", "40x30", "-2,1.2", "1,-1.2"].map(String::from));
+        parse_args(args)
+    };
+    for value in ["nan", "inf", "-inf"] {
+        let error = parse(&["--frames", "3", "--zoom-factor", value]).unwrap_err();
+        assert_eq!(error.exit_code(), 2);
+        assert!(error.to_string().contains("--zoom-factor"), "{}", error);
+    }
+    assert!(parse(&["--frames", "3", "--zoom-factor", "1.5"]).is_ok());
+    for value in ["nan", "inf", "-inf"] {
+        let error = parse(&["--rotate", value]).unwrap_err();
+        assert_eq!(error.exit_code(), 2);
+        assert!(error.to_string().contains("--rotate"), "{}", error);
+    }
+    assert!(parse(&["--rotate", "-30"]).is_ok());
+}
@@ -0,0 +1,195 @@
+//! 多項式のニュートン法によるフラクタル (Newton fractal)
+//!
+//! 各ピクセルの点を初期値としてニュートン法 z ← z - p(z)/p'(z) を繰り返し、
+//! どの根に収束したかと、収束までの回数で色付けする
+
+use num::Complex;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::palette::Sample;
+
+/// 1ステップの移動量がこれより小さくなったら収束したとみなす
+const TOLERANCE: f64 = 1e-9;
+
+/// 複素係数の多項式
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polynomial {
+    /// 次数の高い項から並べた係数 先頭は0でない
+    coefficients: Vec<Complex<f64>>,
+}
+
+impl Polynomial {
+    /// 次数の高い項から並べた係数から作る
+    /// 先頭の0は取り除き、1次以上にならなければNone
+    pub fn new(coefficients: Vec<Complex<f64>>) -> Option<Polynomial> {
+        let leading = coefficients.iter().position(|c| c.norm_sqr() != 0.0)?;
+        let coefficients = coefficients[leading..].to_vec();
+        if coefficients.len() < 2 {
+            return None;
+        }
+        Some(Polynomial { coefficients })
+    }
+
+    pub fn degree(&self) -> usize {
+        self.coefficients.len() - 1
+    }
+
+    /// ホーナー法でp(z)とp'(z)を同時に求める
+    pub fn evaluate(&self, z: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        let mut p = Complex { re: 0.0, im: 0.0 };
+        let mut dp = Complex { re: 0.0, im: 0.0 };
+        for &c in &self.coefficients {
+            dp = dp * z + p;
+            p = p * z + c;
+        }
+        (p, dp)
+    }
+
+    /// Durand-Kerner法で全ての根を求める
+    /// 重根は1つにまとめるので、根の数は次数より少ないことがある
+    pub fn roots(&self) -> Vec<Complex<f64>> {
+        let leading = self.coefficients[0];
+        // 根どうしが対称な位置から始めると動かなくなるので、実軸にも単位円にも乗らない点の累乗を初期値とする
+        let seed = Complex { re: 0.4, im: 0.9 };
+        let mut roots: Vec<Complex<f64>> = (0..self.degree()).map(|k| seed.powu(k as u32)).collect();
+        for _ in 0..1000 {
+            let mut change: f64 = 0.0;
+            for i in 0..roots.len() {
+                let denominator = roots.iter().enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .fold(leading, |product, (_, &r)| product * (roots[i] - r));
+                let step = self.evaluate(roots[i]).0 / denominator;
+                if step.is_finite() {
+                    roots[i] -= step;
+                    change = change.max(step.norm());
+                }
+            }
+            if change < 1e-14 {
+                break;
+            }
+        }
+
+        // 重根は近くに散らばった値になるので、1つにまとめる
+        let mut distinct: Vec<Complex<f64>> = Vec::new();
+        for root in roots {
+            if distinct.iter().all(|r| (r - root).norm() > 1e-6) {
+                distinct.push(root);
+            }
+        }
+        distinct
+    }
+}
+
+/// 次数の高い項から":"で区切った係数をパースする
+/// 係数は実数か、parse_complexの形式の "RE,IM"
+/// 例えば "1:0:0:-1" は z^3 - 1 となる
+impl FromStr for Polynomial {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Polynomial, ()> {
+        let coefficients: Option<Vec<Complex<f64>>> = s.split(':')
+            .map(|c| match f64::from_str(c) {
+                Ok(re) => Some(Complex { re, im: 0.0 }),
+                Err(_) => crate::parse_complex(c),
+            })
+            .collect();
+        Polynomial::new(coefficients.ok_or(())?).ok_or(())
+    }
+}
+
+/// FromStrと同じ形式で書き出す 虚部が0の係数は実数として書く
+impl fmt::Display for Polynomial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, c) in self.coefficients.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            if c.im == 0.0 {
+                write!(f, "{}", c.re)?;
+            } else {
+                write!(f, "{},{}", c.re, c.im)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ニュートン法で描画する多項式と、あらかじめ求めたその根
+#[derive(Debug, Clone, PartialEq)]
+pub struct Newton {
+    pub polynomial: Polynomial,
+    pub roots: Vec<Complex<f64>>,
+}
+
+impl Newton {
+    pub fn new(polynomial: Polynomial) -> Newton {
+        let roots = polynomial.roots();
+        Newton { polynomial, roots }
+    }
+
+    /// zを初期値としてニュートン法を繰り返す
+    /// limit回までに収束すれば、最も近い根の番号と収束までの回数を返す
+    /// 微分が0になったり、収束しなかったりした場合はNone
+    pub fn converge(&self, mut z: Complex<f64>, limit: usize) -> Option<(usize, usize)> {
+        for i in 1..=limit {
+            let (p, dp) = self.polynomial.evaluate(z);
+            let step = p / dp;
+            if !step.is_finite() {
+                return None;
+            }
+            z -= step;
+            if step.norm() < TOLERANCE {
+                return self.nearest_root(z).map(|root| (root, i));
+            }
+        }
+        None
+    }
+
+    fn nearest_root(&self, z: Complex<f64>) -> Option<usize> {
+        (0..self.roots.len()).min_by(|&a, &b| (self.roots[a] - z).norm().total_cmp(&(self.roots[b] - z).norm()))
+    }
+
+    /// 点pointの計算結果 収束しなければNone
+    /// パレットを根の数で等分し、根ごとにその区間の色を使う
+    /// 区間の中では収束の遅いほど先に進める (区間の3/4まで使い、根どうしの境目が分かるようにする)
+    /// 色に変換しない時の値は収束までの回数
+    pub fn sample(&self, point: Complex<f64>, limit: usize) -> Option<Sample> {
+        let (root, count) = self.converge(point, limit)?;
+        let shade = count as f64 / (count as f64 + 10.0);
+        Some(Sample { value: count as f64, t: (root as f64 + 0.75 * shade) / self.roots.len() as f64 })
+    }
+}
+
+#[test]
+fn test_parse_polynomial() {
+    let p: Polynomial = "0:1:0,2:-1.5".parse().unwrap();
+    assert_eq!(p.degree(), 2);
+    assert_eq!(p.to_string(), "1:0,2:-1.5");
+    assert_eq!(p.evaluate(Complex { re: 1.0, im: 1.0 }), (Complex { re: -3.5, im: 4.0 }, Complex { re: 2.0, im: 4.0 }));
+    assert!("3".parse::<Polynomial>().is_err());
+    assert!("0:0:1".parse::<Polynomial>().is_err());
+    assert!("1:x:1".parse::<Polynomial>().is_err());
+    assert!("1:".parse::<Polynomial>().is_err());
+}
+
+#[test]
+fn test_roots_and_newton() {
+    // z^3 - 1 の根は1の3乗根
+    let newton = Newton::new("1:0:0:-1".parse().unwrap());
+    assert_eq!(newton.roots.len(), 3);
+    for root in &newton.roots {
+        assert!((root.powu(3) - 1.0).norm() < 1e-12);
+    }
+    let one = newton.roots.iter().position(|r| (r - 1.0).norm() < 1e-9).unwrap();
+    let (root, count) = newton.converge(Complex { re: 2.0, im: 0.1 }, 50).unwrap();
+    assert_eq!(root, one);
+    assert!(count > 1 && count < 20);
+    // 原点では微分が0になる
+    assert_eq!(newton.converge(Complex { re: 0.0, im: 0.0 }, 50), None);
+
+    // (z - 1)^2 (z + 1) = z^3 - z^2 - z + 1 の重根は1つにまとめる
+    let newton = Newton::new("1:-1:-1:1".parse().unwrap());
+    assert_eq!(newton.roots.len(), 2);
+    assert!(newton.converge(Complex { re: 3.0, im: 0.5 }, 100).is_some());
+}
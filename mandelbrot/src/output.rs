@@ -0,0 +1,393 @@
+//! 画像ファイルの書き出し
+//!
+//! 出力先のファイル名の拡張子で形式を選ぶ
+//! PNG以外はどれも単純な形式なので、ライブラリを使わずにバイト列を組み立てる
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::error::MandelError;
+use crate::palette::PixelFormat;
+use crate::RenderParams;
+
+/// PNGのtEXtチャンクに記録する描画の指定のキーの接頭辞
+pub const TEXT_PREFIX: &str = "mandelbrot:";
+
+/// 出力ファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    /// PGM (グレースケール) またはPPM (RGB) のバイナリ形式
+    Pnm,
+    /// 圧縮しないベースラインTIFF
+    Tiff,
+    /// 小さなヘッダの後にリトルエンディアンのf32の繰り返し回数を並べた形式
+    /// ヘッダは"MBIT"、幅と高さ (リトルエンディアンのu32) の12バイト
+    Raw,
+    /// NumPyの.npy形式 (numpy.loadで読み込める)
+    Npy,
+}
+
+impl OutputFormat {
+    /// ファイル名の拡張子から形式を選ぶ 知らない拡張子はPNGとする
+    pub fn from_filename(filename: &str) -> OutputFormat {
+        let extension = match filename.rfind('.') {
+            Some(index) if !filename[index..].contains('/') => filename[index + 1..].to_ascii_lowercase(),
+            _ => String::new(),
+        };
+        match extension.as_str() {
+            "pgm" | "ppm" | "pnm" => OutputFormat::Pnm,
+            "tif" | "tiff" => OutputFormat::Tiff,
+            "raw" => OutputFormat::Raw,
+            "npy" => OutputFormat::Npy,
+            _ => OutputFormat::Png,
+        }
+    }
+
+    /// 繰り返し回数を保存するための形式なら、ピクセル形式の既定値をPixelFormat::IterF32にする
+    pub fn prefers_counts(self) -> bool {
+        matches!(self, OutputFormat::Raw | OutputFormat::Npy)
+    }
+
+    /// このピクセル形式を書き出せるかどうか
+    /// IterF32はPNGで表せないので、.raw .npy .tifのいずれかで書き出す
+    pub fn supports(self, format: PixelFormat) -> bool {
+        match self {
+            OutputFormat::Tiff | OutputFormat::Npy => true,
+            OutputFormat::Png => format != PixelFormat::IterF32,
+            OutputFormat::Pnm => format != PixelFormat::Rgba8 && format != PixelFormat::IterF32,
+            OutputFormat::Raw => format == PixelFormat::IterF32,
+        }
+    }
+}
+
+/// 大きさがboundsのバッファpixelsを、拡張子で選んだ形式でfilenameに書き出す
+pub fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize), format: PixelFormat) -> Result<(), MandelError> {
+    write_image_with_text(filename, pixels, bounds, format, &[])
+}
+
+/// write_imageと同じだが、PNGの場合はtextの(キー, 値)をtEXtチャンクとして書き込む
+/// 他の形式ではtextは無視する
+pub fn write_image_with_text(filename: &str,
+                             pixels: &[u8],
+                             bounds: (usize, usize),
+                             format: PixelFormat,
+                             text: &[(String, String)],
+) -> Result<(), MandelError> {
+    let output = OutputFormat::from_filename(filename);
+    if !output.supports(format) {
+        return Err(MandelError::encoding(filename, format!("{:?} output cannot store {:?} pixels", output, format)));
+    }
+    let bytes = match output {
+        OutputFormat::Png => {
+            let mut writer = png_writer(filename, bounds, format, text)?;
+            writer.write_image_data(pixels).map_err(|e| png_error(filename, e))?;
+            return writer.finish().map_err(|e| png_error(filename, e));
+        }
+        OutputFormat::Pnm => pnm_bytes(pixels, bounds, format),
+        OutputFormat::Tiff => tiff_bytes(pixels, bounds, format),
+        OutputFormat::Raw => raw_bytes(pixels, bounds),
+        OutputFormat::Npy => npy_bytes(pixels, bounds, format),
+    };
+    std::fs::write(filename, bytes).map_err(|e| MandelError::io(filename, e))
+}
+
+/// pngクレートのエラーを、ファイルの読み書きの失敗とそれ以外に分ける
+fn png_error(filename: &str, error: png::EncodingError) -> MandelError {
+    match error {
+        png::EncodingError::IoError(e) => MandelError::io(filename, e),
+        e => MandelError::encoding(filename, e),
+    }
+}
+
+/// filenameを作ってPNGのヘッダとtEXtチャンクを書き込む
+/// PixelFormat::IterF32はPNGで表せないのでエラーとする
+fn png_writer(filename: &str, bounds: (usize, usize), format: PixelFormat, text: &[(String, String)])
+    -> Result<png::Writer<BufWriter<File>>, MandelError> {
+    let (color, depth) = match format {
+        PixelFormat::Gray8 => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        PixelFormat::Gray16 => (png::ColorType::Grayscale, png::BitDepth::Sixteen),
+        PixelFormat::Rgb8 => (png::ColorType::Rgb, png::BitDepth::Eight),
+        PixelFormat::Rgba8 => (png::ColorType::Rgba, png::BitDepth::Eight),
+        PixelFormat::IterF32 => return Err(MandelError::encoding(filename, "PNG output cannot store IterF32 pixels")),
+    };
+    let output = BufWriter::new(File::create(filename).map_err(|e| MandelError::io(filename, e))?);
+    let mut encoder = png::Encoder::new(output, bounds.0 as u32, bounds.1 as u32);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    for (key, value) in text {
+        encoder.add_text_chunk(key.clone(), value.clone()).map_err(|e| png_error(filename, e))?;
+    }
+    encoder.write_header().map_err(|e| png_error(filename, e))
+}
+
+/// 大きさboundsの画像をparamsで描画した時の指定を、tEXtチャンクの(キー, 値)にする
+/// ピクセルの値を変える指定は全て記録する (スレッド数やkernelなど結果を変えないものは除く)
+/// キーは接頭辞TEXT_PREFIXの後にコマンドラインのオプションの名前を付けたもので、--fromでオプションに戻せる
+/// upper_left lower_rightは画像の角を"RE,IM"の形にしたもの (深い拡大モードでは10進数のまま渡す)
+pub fn render_text(params: &RenderParams, bounds: (usize, usize), upper_left: String, lower_right: String)
+    -> Vec<(String, String)> {
+    let corner = |p: num::Complex<f64>| format!("{},{}", p.re, p.im);
+    let mut text = vec![
+        ("pixels".to_string(), format!("{}x{}", bounds.0, bounds.1)),
+        ("upper-left".to_string(), upper_left),
+        ("lower-right".to_string(), lower_right),
+        ("max-iter".to_string(), params.limit.to_string()),
+        ("formula".to_string(), params.formula.to_string()),
+        ("palette".to_string(), params.palette.to_string()),
+    ];
+    if let Some(c) = params.julia {
+        text.push(("julia".to_string(), corner(c)));
+    }
+    let mut flag = |name: &str, set: bool| {
+        if set {
+            text.push((name.to_string(), "true".to_string()));
+        }
+    };
+    flag("smooth", params.smooth);
+    flag("distance", params.distance);
+    flag("equalize", params.equalize);
+    flag("alpha", params.format == PixelFormat::Rgba8);
+    flag("jitter", params.jitter);
+    flag("no-shortcuts", !params.shortcuts);
+    // tEXtチャンクはPNGにしか書かないので、深さはPNGで表せる8か16のどちらか
+    if params.format == PixelFormat::Gray16 {
+        text.push(("depth".to_string(), "16".to_string()));
+    }
+    if params.samples > 1 {
+        text.push(("supersample".to_string(), params.samples.to_string()));
+    }
+    if let Some(newton) = &params.newton {
+        text.push(("newton".to_string(), newton.polynomial.to_string()));
+    }
+    if let Some(trap) = &params.trap {
+        text.push(("trap".to_string(), trap.to_string()));
+    }
+    if params.rotation != 0.0 {
+        text.push(("rotate".to_string(), params.rotation.to_degrees().to_string()));
+    }
+    for (key, _) in text.iter_mut() {
+        key.insert_str(0, TEXT_PREFIX);
+    }
+    text.insert(0, (String::from("Software"), String::from("mandelbrot")));
+    text
+}
+
+/// PNGファイルのtEXtチャンクを(キー, 値)の列として読み出す
+pub fn read_text(filename: &str) -> Result<Vec<(String, String)>, MandelError> {
+    let decoder = png::Decoder::new(File::open(filename).map_err(|e| MandelError::io(filename, e))?);
+    let reader = decoder.read_info().map_err(|e| match e {
+        png::DecodingError::IoError(e) => MandelError::io(filename, e),
+        e => MandelError::encoding(filename, e),
+    })?;
+    Ok(reader.info().uncompressed_latin1_text.iter()
+        .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+        .collect())
+}
+
+/// 画像を上から数行ずつ受け取り、圧縮しながらPNGファイルに書き込む
+/// render_stripsと組み合わせて、画像全体をメモリに置かずに書き出すのに使う
+pub struct PngStream {
+    writer: png::StreamWriter<'static, BufWriter<File>>,
+    /// エラーメッセージに使うファイル名
+    filename: String,
+}
+
+impl PngStream {
+    /// filenameを作ってヘッダとtEXtチャンクを書き込む
+    pub fn create(filename: &str, bounds: (usize, usize), format: PixelFormat, text: &[(String, String)])
+        -> Result<PngStream, MandelError> {
+        let writer = png_writer(filename, bounds, format, text)?;
+        Ok(PngStream {
+            writer: writer.into_stream_writer().map_err(|e| png_error(filename, e))?,
+            filename: filename.to_string(),
+        })
+    }
+
+    /// 続きの行を書き込む rowsは行の途中で区切れていてもよい
+    pub fn write_rows(&mut self, rows: &[u8]) -> Result<(), MandelError> {
+        self.writer.write_all(rows).map_err(|e| MandelError::io(&self.filename, e))
+    }
+
+    /// 最後の行まで書き込んだら呼び、ファイルを閉じる
+    pub fn finish(self) -> Result<(), MandelError> {
+        let filename = self.filename;
+        self.writer.finish().map_err(|e| png_error(&filename, e))
+    }
+}
+
+/// PGM (P5) またはPPM (P6)
+/// 16ビットのPGMはビッグエンディアンなので、Gray16のバッファをそのまま使える
+fn pnm_bytes(pixels: &[u8], bounds: (usize, usize), format: PixelFormat) -> Vec<u8> {
+    let (magic, max) = match format {
+        PixelFormat::Gray8 => ("P5", 255),
+        PixelFormat::Gray16 => ("P5", 65535),
+        PixelFormat::Rgb8 => ("P6", 255),
+        PixelFormat::Rgba8 | PixelFormat::IterF32 => unreachable!(),
+    };
+    let mut bytes = format!("{}\n{} {}\n{}\n", magic, bounds.0, bounds.1, max).into_bytes();
+    bytes.extend_from_slice(pixels);
+    bytes
+}
+
+/// リトルエンディアンのTIFF
+/// ヘッダの直後に全ピクセルを1つのストリップとして置き、その後にIFDを置く
+fn tiff_bytes(pixels: &[u8], bounds: (usize, usize), format: PixelFormat) -> Vec<u8> {
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+
+    let (samples, bits, photometric): (u16, u16, u16) = match format {
+        PixelFormat::Gray8 => (1, 8, 1),
+        PixelFormat::Gray16 => (1, 16, 1),
+        PixelFormat::Rgb8 => (3, 8, 2),
+        PixelFormat::Rgba8 => (4, 8, 2),
+        PixelFormat::IterF32 => (1, 32, 1),
+    };
+
+    let mut bytes = Vec::with_capacity(pixels.len() + 256);
+    bytes.extend_from_slice(b"II");
+    bytes.extend_from_slice(&42u16.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    let strip_offset = bytes.len() as u32;
+    if format == PixelFormat::Gray16 {
+        // Gray16のバッファはPNGに合わせてビッグエンディアンなので並べ替える
+        for pair in pixels.chunks(2) {
+            bytes.extend_from_slice(&[pair[1], pair[0]]);
+        }
+    } else {
+        bytes.extend_from_slice(pixels);
+    }
+    // IFDは偶数番地から始める
+    if bytes.len() % 2 == 1 {
+        bytes.push(0);
+    }
+    let ifd_offset = bytes.len() as u32;
+    bytes[4..8].copy_from_slice(&ifd_offset.to_le_bytes());
+
+    // (タグ, 型, 個数, 値) タグの番号順に並べる
+    // SHORTが1つなら値の欄にそのまま入れる 3つ以上のBitsPerSampleはIFDの後に置き、その位置を値とする
+    let mut entries: Vec<(u16, u16, u32, u32)> = vec![
+        (256, LONG, 1, bounds.0 as u32),
+        (257, LONG, 1, bounds.1 as u32),
+        (258, SHORT, samples as u32, bits as u32),
+        (259, SHORT, 1, 1),
+        (262, SHORT, 1, photometric as u32),
+        (273, LONG, 1, strip_offset),
+        (277, SHORT, 1, samples as u32),
+        (278, LONG, 1, bounds.1 as u32),
+        (279, LONG, 1, pixels.len() as u32),
+        (284, SHORT, 1, 1),
+    ];
+    if format == PixelFormat::Rgba8 {
+        // 4つ目のサンプルは乗算されていないアルファ
+        entries.push((338, SHORT, 1, 2));
+    }
+    if format == PixelFormat::IterF32 {
+        // サンプルは浮動小数点数
+        entries.push((339, SHORT, 1, 3));
+    }
+    let extra_offset = ifd_offset + 2 + entries.len() as u32 * 12 + 4;
+
+    bytes.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for &(tag, kind, count, value) in &entries {
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&count.to_le_bytes());
+        if tag == 258 && samples > 2 {
+            bytes.extend_from_slice(&extra_offset.to_le_bytes());
+        } else if kind == SHORT {
+            bytes.extend_from_slice(&(value as u16).to_le_bytes());
+            bytes.extend_from_slice(&[0, 0]);
+        } else {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    // 次のIFDはない
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    if samples > 2 {
+        for _ in 0..samples {
+            bytes.extend_from_slice(&bits.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// "MBIT"、幅、高さの後にf32の繰り返し回数を並べる
+fn raw_bytes(pixels: &[u8], bounds: (usize, usize)) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(12 + pixels.len());
+    bytes.extend_from_slice(b"MBIT");
+    bytes.extend_from_slice(&(bounds.0 as u32).to_le_bytes());
+    bytes.extend_from_slice(&(bounds.1 as u32).to_le_bytes());
+    bytes.extend_from_slice(pixels);
+    bytes
+}
+
+/// .npy形式 (バージョン1.0)
+/// 配列の形は(高さ, 幅)、RGBとRGBAでは(高さ, 幅, チャンネル数)とする
+fn npy_bytes(pixels: &[u8], bounds: (usize, usize), format: PixelFormat) -> Vec<u8> {
+    let (descr, shape) = match format {
+        PixelFormat::Gray8 => ("|u1", format!("({}, {})", bounds.1, bounds.0)),
+        PixelFormat::Gray16 => (">u2", format!("({}, {})", bounds.1, bounds.0)),
+        PixelFormat::Rgb8 => ("|u1", format!("({}, {}, 3)", bounds.1, bounds.0)),
+        PixelFormat::Rgba8 => ("|u1", format!("({}, {}, 4)", bounds.1, bounds.0)),
+        PixelFormat::IterF32 => ("<f4", format!("({}, {})", bounds.1, bounds.0)),
+    };
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+    // マジック、バージョン、ヘッダ長の10バイトを含めて64の倍数になるよう空白で埋め、改行で終える
+    let total = (10 + header.len() + 1).div_ceil(64) * 64;
+    while 10 + header.len() + 1 < total {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(total + pixels.len());
+    bytes.extend_from_slice(b"\x93NUMPY\x01\x00");
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend_from_slice(pixels);
+    bytes
+}
+
+#[test]
+fn test_output_format_from_filename() {
+    assert_eq!(OutputFormat::from_filename("mandel.png"), OutputFormat::Png);
+    assert_eq!(OutputFormat::from_filename("out/mandel.PGM"), OutputFormat::Pnm);
+    assert_eq!(OutputFormat::from_filename("mandel.tif"), OutputFormat::Tiff);
+    assert_eq!(OutputFormat::from_filename("counts.npy"), OutputFormat::Npy);
+    assert_eq!(OutputFormat::from_filename("out.d/mandel"), OutputFormat::Png);
+    assert!(!OutputFormat::Pnm.supports(PixelFormat::Rgba8));
+    assert!(!OutputFormat::Png.supports(PixelFormat::IterF32));
+    assert!(OutputFormat::Tiff.supports(PixelFormat::IterF32));
+    assert!(!OutputFormat::Raw.supports(PixelFormat::Gray8));
+}
+
+#[test]
+fn test_png_text_round_trip() {
+    let filename = std::env::temp_dir().join(format!("mandelbrot-text-test-{}.png", std::process::id()));
+    let filename = filename.to_str().unwrap();
+    let text = vec![(String::from("mandelbrot:max-iter"), String::from("1000")),
+                    (String::from("mandelbrot:palette"), String::from("0:000000,1:ffffff"))];
+    write_image_with_text(filename, &[0, 128, 255, 64], (2, 2), PixelFormat::Gray8, &text).unwrap();
+    assert_eq!(read_text(filename).unwrap(), text);
+    std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn test_headers() {
+    let pixels = [1, 2, 3, 4, 5, 6];
+    assert_eq!(pnm_bytes(&pixels, (2, 1), PixelFormat::Rgb8), b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    assert_eq!(&raw_bytes(&pixels, (3, 2))[..12], b"MBIT\x03\x00\x00\x00\x02\x00\x00\x00");
+
+    let npy = npy_bytes(&pixels, (3, 2), PixelFormat::Gray8);
+    assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+    assert_eq!((npy.len() - pixels.len()) % 64, 0);
+    let header = std::str::from_utf8(&npy[10..npy.len() - pixels.len()]).unwrap();
+    assert!(header.starts_with("{'descr': '|u1', 'fortran_order': False, 'shape': (2, 3), }"));
+    assert!(header.ends_with('\n'));
+
+    let tiff = tiff_bytes(&[0x12, 0x34], (1, 1), PixelFormat::Gray16);
+    assert_eq!(&tiff[..4], b"II\x2a\x00");
+    // ピクセルはリトルエンディアンに並べ替えられる
+    assert_eq!(&tiff[8..10], &[0x34, 0x12]);
+    assert_eq!(u32::from_le_bytes([tiff[4], tiff[5], tiff[6], tiff[7]]), 10);
+}
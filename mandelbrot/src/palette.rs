@@ -0,0 +1,334 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// 出力画像のピクセル形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Gray8,
+    /// 16ビットグレースケール (PNGに合わせてビッグエンディアン)
+    Gray16,
+    Rgb8,
+    Rgba8,
+    /// 色に変換せず、繰り返し回数をリトルエンディアンのf32でそのまま出力する
+    /// 集合に含まれるピクセルはNaNとなる
+    IterF32,
+}
+
+impl PixelFormat {
+    /// 1ピクセルあたりのバイト数
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Gray16 => 2,
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::Rgba8 => 4,
+            PixelFormat::IterF32 => 4,
+        }
+    }
+
+    /// write_pixelで書き込んだバイト列をチャンネルごとの数値に戻す
+    /// 使わないチャンネルは0.0になる
+    pub fn decode(self, bytes: &[u8]) -> [f64; 4] {
+        let mut values = [0.0; 4];
+        match self {
+            PixelFormat::Gray16 => values[0] = u16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            PixelFormat::IterF32 => values[0] = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            _ => {
+                for (value, &byte) in values.iter_mut().zip(bytes) {
+                    *value = byte as f64;
+                }
+            }
+        }
+        values
+    }
+
+    /// decodeの逆 チャンネルごとの数値を丸めてoutに書き込む
+    pub fn encode(self, values: [f64; 4], out: &mut [u8]) {
+        match self {
+            PixelFormat::Gray16 => out.copy_from_slice(&(values[0].round() as u16).to_be_bytes()),
+            PixelFormat::IterF32 => out.copy_from_slice(&(values[0] as f32).to_le_bytes()),
+            _ => {
+                for (byte, &value) in out.iter_mut().zip(&values) {
+                    *byte = value.round() as u8;
+                }
+            }
+        }
+    }
+}
+
+/// 1つの点の計算結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// 色に変換せずに出力する時の値 (脱出までの繰り返し回数など)
+    pub value: f64,
+    /// パレットに渡す0.0〜1.0の値
+    pub t: f64,
+}
+
+impl Sample {
+    /// 脱出までの回数countを繰り返し上限limitで割った値で色付けする
+    pub fn count(count: f64, limit: usize) -> Sample {
+        Sample { value: count, t: count / limit as f64 }
+    }
+}
+
+/// 位置(0.0〜1.0)と色の組の列で表すグラデーション
+/// 位置は昇順に並んでいることを前提とする
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    stops: Vec<(f64, [u8; 3])>,
+}
+
+impl Gradient {
+    /// stopsが空、または位置が昇順でない場合はNoneを返す
+    pub fn new(stops: Vec<(f64, [u8; 3])>) -> Option<Gradient> {
+        if stops.is_empty() || stops.windows(2).any(|w| w[0].0 > w[1].0) {
+            return None;
+        }
+        Some(Gradient { stops })
+    }
+
+    /// tの位置の色を前後のストップから線形補間して返す
+    pub fn at(&self, t: f64) -> [u8; 3] {
+        let first = self.stops[0];
+        if t <= first.0 {
+            return first.1;
+        }
+        for w in self.stops.windows(2) {
+            let ((p0, c0), (p1, c1)) = (w[0], w[1]);
+            if t <= p1 {
+                let f = if p1 > p0 { (t - p0) / (p1 - p0) } else { 1.0 };
+                return lerp(c0, c1, f);
+            }
+        }
+        self.stops[self.stops.len() - 1].1
+    }
+}
+
+/// "0:000764,0.5:ff0000,1:ffffff" のような 位置:RRGGBB をカンマで並べた文字列をパースする
+impl FromStr for Gradient {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Gradient, ()> {
+        let mut stops = Vec::new();
+        for stop in s.split(',') {
+            let (pos, hex) = stop.split_once(':').ok_or(())?;
+            let pos = f64::from_str(pos).map_err(|_| ())?;
+            stops.push((pos, parse_hex_color(hex).ok_or(())?));
+        }
+        Gradient::new(stops).ok_or(())
+    }
+}
+
+/// FromStrと同じ形式で書き出す
+impl fmt::Display for Gradient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (pos, [r, g, b])) in self.stops.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:{:02x}{:02x}{:02x}", pos, r, g, b)?;
+        }
+        Ok(())
+    }
+}
+
+/// 脱出までの繰り返し回数から色を決めるパレット
+#[derive(Debug, Clone, PartialEq)]
+pub enum Palette {
+    /// 従来どおりのグレースケール (回数が少ないほど明るい)
+    Gray,
+    /// 任意のグラデーション
+    Gradient(Gradient),
+    /// 色相を一周するHSVサイクル
+    Hsv,
+}
+
+impl Palette {
+    /// Ultra Fractalのデフォルトに似た青〜白〜橙のグラデーション
+    pub fn ultra_fractal() -> Palette {
+        Palette::Gradient(Gradient {
+            stops: vec![
+                (0.0, [0, 7, 100]),
+                (0.16, [32, 107, 203]),
+                (0.42, [237, 255, 255]),
+                (0.6425, [255, 170, 0]),
+                (0.8575, [0, 2, 0]),
+                (1.0, [0, 7, 100]),
+            ],
+        })
+    }
+
+    /// 黒〜赤〜黄〜白の炎のようなグラデーション
+    pub fn fire() -> Palette {
+        Palette::Gradient(Gradient {
+            stops: vec![
+                (0.0, [0, 0, 0]),
+                (0.33, [200, 20, 0]),
+                (0.66, [255, 200, 0]),
+                (1.0, [255, 255, 255]),
+            ],
+        })
+    }
+
+    /// tは脱出までの回数を繰り返し上限で割った0.0〜1.0の値
+    pub fn color(&self, t: f64) -> [u8; 3] {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Palette::Gray => {
+                let v = 255 - (t * 255.0).round() as u8;
+                [v, v, v]
+            }
+            Palette::Gradient(gradient) => gradient.at(t),
+            Palette::Hsv => hsv_to_rgb(t * 360.0, 1.0, 1.0),
+        }
+    }
+
+    /// 点の計算結果sampleをformatの形式でoutに書き込む
+    /// 色に変換する形式ではsample.tをパレットに渡す
+    /// sampleがNoneのピクセルはマンデルブロ集合に含まれるとみなし黒 (RGBAでは透明) で塗る
+    /// outの長さはformat.channels()と一致していなければならない
+    pub fn write_pixel(&self, sample: Option<Sample>, format: PixelFormat, out: &mut [u8]) {
+        let t = sample.map(|sample| sample.t.clamp(0.0, 1.0));
+        let rgb = match t {
+            None => [0, 0, 0],
+            Some(t) => self.color(t),
+        };
+        match format {
+            PixelFormat::Gray8 => out[0] = rgb[0],
+            PixelFormat::Gray16 => {
+                // 8ビットに丸めると上限の大きい時に回数の違いが失われるので、tから直接求める
+                let v = match t {
+                    None => 0,
+                    Some(t) => 65535 - (t * 65535.0).round() as u16,
+                };
+                out.copy_from_slice(&v.to_be_bytes());
+            }
+            PixelFormat::Rgb8 => out.copy_from_slice(&rgb),
+            PixelFormat::Rgba8 => {
+                out[..3].copy_from_slice(&rgb);
+                out[3] = if t.is_some() { 255 } else { 0 };
+            }
+            PixelFormat::IterF32 => {
+                let v = sample.map_or(f32::NAN, |sample| sample.value as f32);
+                out.copy_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// 組み込みパレットの名前をパースする
+/// 名前でなければGradientの形式として読む
+impl FromStr for Palette {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Palette, ()> {
+        match s {
+            "gray" => Ok(Palette::Gray),
+            "ultra" => Ok(Palette::ultra_fractal()),
+            "fire" => Ok(Palette::fire()),
+            "hsv" => Ok(Palette::Hsv),
+            _ => Gradient::from_str(s).map(Palette::Gradient),
+        }
+    }
+}
+
+/// 組み込みパレットは名前で、それ以外はGradientの形式で書き出す
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Palette::Gray => write!(f, "gray"),
+            Palette::Hsv => write!(f, "hsv"),
+            palette if *palette == Palette::ultra_fractal() => write!(f, "ultra"),
+            palette if *palette == Palette::fire() => write!(f, "fire"),
+            Palette::Gradient(gradient) => write!(f, "{}", gradient),
+        }
+    }
+}
+
+/// "ff8000" や "#ff8000" の形式の色をパースする
+fn parse_hex_color(s: &str) -> Option<[u8; 3]> {
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if s.len() != 6 || !s.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn lerp(a: [u8; 3], b: [u8; 3], f: f64) -> [u8; 3] {
+    let mix = |x: u8, y: u8| (x as f64 + (y as f64 - x as f64) * f).round() as u8;
+    [mix(a[0], b[0]), mix(a[1], b[1]), mix(a[2], b[2])]
+}
+
+/// hは0〜360度、s vは0.0〜1.0
+fn hsv_to_rgb(h: f64, s: f64, v: f64) -> [u8; 3] {
+    let c = v * s;
+    let h = (h % 360.0) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    let to_byte = |f: f64| ((f + m) * 255.0).round() as u8;
+    [to_byte(r), to_byte(g), to_byte(b)]
+}
+
+#[test]
+fn test_parse_gradient() {
+    let gradient: Gradient = "0:000000,1:#ffffff".parse().unwrap();
+    assert_eq!(gradient.at(0.0), [0, 0, 0]);
+    assert_eq!(gradient.at(0.5), [128, 128, 128]);
+    assert_eq!(gradient.at(1.0), [255, 255, 255]);
+    assert!("0:000000,1:fff".parse::<Gradient>().is_err());
+    assert!("1:000000,0:ffffff".parse::<Gradient>().is_err());
+    assert!("".parse::<Gradient>().is_err());
+}
+
+#[test]
+fn test_palette_display_round_trip() {
+    let gradient = Palette::Gradient("0:000764,0.5:FF0000,1:ffffff".parse().unwrap());
+    assert_eq!(gradient.to_string(), "0:000764,0.5:ff0000,1:ffffff");
+    for palette in [Palette::Gray, Palette::Hsv, Palette::ultra_fractal(), Palette::fire(), gradient] {
+        assert_eq!(palette.to_string().parse(), Ok(palette));
+    }
+    assert_eq!(Palette::fire().to_string(), "fire");
+}
+
+#[test]
+fn test_pixel_format_round_trip() {
+    for &(format, bytes) in &[(PixelFormat::Gray16, &[0x12, 0x34][..]),
+                              (PixelFormat::Rgba8, &[1, 2, 3, 4][..]),
+                              (PixelFormat::IterF32, &1234.5f32.to_le_bytes()[..])] {
+        let mut out = vec![0; format.channels()];
+        format.encode(format.decode(bytes), &mut out);
+        assert_eq!(out, bytes);
+    }
+}
+
+#[test]
+fn test_palette_color() {
+    assert_eq!(Palette::Gray.color(0.0), [255, 255, 255]);
+    assert_eq!(Palette::Gray.color(3.0 / 255.0), [252, 252, 252]);
+    assert_eq!(Palette::Hsv.color(0.0), [255, 0, 0]);
+    assert_eq!(Palette::Hsv.color(1.0 / 3.0), [0, 255, 0]);
+
+    let mut rgba = [1; 4];
+    Palette::fire().write_pixel(None, PixelFormat::Rgba8, &mut rgba);
+    assert_eq!(rgba, [0, 0, 0, 0]);
+
+    // 上限が大きくても16ビットなら隣り合う回数が区別できる
+    let (mut a, mut b) = ([0; 2], [0; 2]);
+    Palette::Gray.write_pixel(Some(Sample::count(1000.0, 10000)), PixelFormat::Gray16, &mut a);
+    Palette::Gray.write_pixel(Some(Sample::count(1001.0, 10000)), PixelFormat::Gray16, &mut b);
+    assert_ne!(a, b);
+
+    let mut raw = [0; 4];
+    Palette::Gray.write_pixel(Some(Sample::count(123456.0, 1000000)), PixelFormat::IterF32, &mut raw);
+    assert_eq!(f32::from_le_bytes(raw), 123456.0);
+}
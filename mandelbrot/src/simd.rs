@@ -0,0 +1,61 @@
+//! 複数のピクセルを並べて同時に計算するescape_time
+//!
+//! LANES個の点の実部と虚部をそれぞれ配列にまとめ、全レーンに同じ演算を行う
+//! レーンごとに分岐しない形にしておくと、コンパイラがSIMD命令に変換できる
+//! 脱出したレーンはマスクで記録だけして計算を続け、全レーンが脱出したら打ち切る
+
+use num::Complex;
+
+use crate::Escape;
+
+/// 同時に計算する点の数
+pub const LANES: usize = 4;
+
+/// LANES個の点についてescape_time_bailoutと同じ判定をまとめて行う (漸化式はz = z * z + cのみ)
+pub fn escape_time_lanes(z: [Complex<f64>; LANES], c: [Complex<f64>; LANES], limit: usize, bailout_sqr: f64)
+    -> [Option<Escape>; LANES] {
+    let mut zr = z.map(|z| z.re);
+    let mut zi = z.map(|z| z.im);
+    let cr = c.map(|c| c.re);
+    let ci = c.map(|c| c.im);
+
+    let mut result = [None; LANES];
+    let mut escaped = [false; LANES];
+    for i in 0..limit {
+        let mut norm = [0.0; LANES];
+        for lane in 0..LANES {
+            norm[lane] = zr[lane] * zr[lane] + zi[lane] * zi[lane];
+        }
+        for lane in 0..LANES {
+            if !escaped[lane] && norm[lane] > bailout_sqr {
+                escaped[lane] = true;
+                result[lane] = Some(Escape { count: i, z: Complex { re: zr[lane], im: zi[lane] } });
+            }
+        }
+        if escaped.iter().all(|&e| e) {
+            break;
+        }
+        for lane in 0..LANES {
+            let re = zr[lane] * zr[lane] - zi[lane] * zi[lane] + cr[lane];
+            let im = 2.0 * zr[lane] * zi[lane] + ci[lane];
+            zr[lane] = re;
+            zi[lane] = im;
+        }
+    }
+    result
+}
+
+#[test]
+fn test_escape_time_lanes_matches_scalar() {
+    use crate::formula::Formula;
+
+    let zero = Complex { re: 0.0, im: 0.0 };
+    let c = [Complex { re: 0.0, im: 0.0 }, Complex { re: 1.0, im: 0.0 },
+             Complex { re: -0.75, im: 0.1 }, Complex { re: -1.2, im: 0.35 }];
+    for bailout_sqr in [crate::BAILOUT_SQR, crate::SMOOTH_BAILOUT_SQR] {
+        let lanes = escape_time_lanes([zero; LANES], c, 1000, bailout_sqr);
+        for lane in 0..LANES {
+            assert_eq!(lanes[lane], crate::escape_time_bailout(zero, c[lane], 1000, &Formula::Mandelbrot, bailout_sqr));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Webの地図ビューアで眺めるためのタイルサーバ
+//!
+//! 地図と同じく、ズームレベルzでは範囲全体を縦横2^z枚ずつの正方形のタイルに分け、
+//! /tiles/{z}/{x}/{y}.png でx列y行のタイルを返す
+//! タイルは要求された時に描画し、ディレクトリに保存して次からはそれを返す
+
+use num::Complex;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::cache;
+use crate::error::MandelError;
+use crate::{pixel_to_point, render_image, write_image, RenderParams, Viewport};
+
+/// タイルを保存したディレクトリに置く、描画の指定を書いたファイルの名前
+const KEY_FILE: &str = "tiles.txt";
+
+/// これより深いズームレベルではf64の精度が足りず、ピクセルが区別できなくなる
+pub const MAX_ZOOM: u32 = 40;
+
+/// ズームレベル0のタイル1枚に描く範囲と、タイルの大きさ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileMap {
+    /// ズームレベル0のタイルの中心
+    pub center: Complex<f64>,
+    /// ズームレベル0のタイルの実軸方向の幅 (タイルは正方形なので高さも同じ)
+    pub width: f64,
+    /// タイルの一辺のピクセル数
+    pub tile_size: usize,
+}
+
+impl TileMap {
+    /// ズームレベルzのx列y行のタイルに描く範囲
+    /// ズームレベルzの全体を一辺tile_size * 2^zピクセルの画像とみなし、タイルの角をpixel_to_pointで求める
+    /// タイルの番号が範囲の外ならNone
+    pub fn viewport(&self, z: u32, x: usize, y: usize) -> Option<Viewport> {
+        let tiles = 1usize.checked_shl(z).filter(|_| z <= MAX_ZOOM)?;
+        if x >= tiles || y >= tiles {
+            return None;
+        }
+        let size = self.tile_size;
+        let world = Viewport::centered((size * tiles, size * tiles), self.center, self.width);
+        let corner = |x: usize, y: usize| pixel_to_point(world.bounds, (x * size, y * size), world.upper_left, world.lower_right);
+        Some(Viewport { bounds: (size, size), upper_left: corner(x, y), lower_right: corner(x + 1, y + 1) })
+    }
+}
+
+/// "/tiles/3/5/2.png" のようなパスをズームレベルと列と行に分ける
+pub fn parse_tile_path(path: &str) -> Option<(u32, usize, usize)> {
+    let rest = path.strip_prefix("/tiles/")?.strip_suffix(".png")?;
+    let mut parts = rest.split('/');
+    let z = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((z, x, y))
+}
+
+/// "/" で返すページ
+/// Leafletでタイルを表示する 地図の座標系は使わないのでCRS.Simpleにする
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>mandelbrot</title>
+<link rel="stylesheet" href="https://unpkg.com/leaflet@1.9.4/dist/leaflet.css">
+<script src="https://unpkg.com/leaflet@1.9.4/dist/leaflet.js"></script>
+<style>html, body, #map { height: 100%; margin: 0; background: #000; }</style>
+</head>
+<body>
+<div id="map"></div>
+<script>
+const size = TILE_SIZE;
+const map = L.map('map', { crs: L.CRS.Simple, minZoom: 0, maxZoom: MAX_ZOOM });
+L.tileLayer('/tiles/{z}/{x}/{y}.png', { tileSize: size, noWrap: true, maxZoom: MAX_ZOOM,
+                                        bounds: [[0, 0], [-size, size]] }).addTo(map);
+map.setView([-size / 2, size / 2], 0);
+</script>
+</body>
+</html>
+"#;
+
+/// タイルを描画して返すHTTPサーバ
+#[derive(Debug, Clone)]
+pub struct TileServer {
+    pub map: TileMap,
+    pub params: RenderParams,
+    /// 描画したタイルを {z}/{x}/{y}.png として保存するディレクトリ
+    pub cache: PathBuf,
+    /// 描画の指定を表す文字列
+    /// ディレクトリに保存したものと一致しなければ、別の描画のタイルとみなして使わない
+    pub key: String,
+    /// trueなら要求ごとに描画したかどうかと時間を表示する
+    pub verbose: bool,
+}
+
+impl TileServer {
+    /// addrで待ち受けて、要求を1つずつ処理する 終了しない
+    pub fn run(&self, addr: &str) -> Result<(), MandelError> {
+        self.prepare()?;
+        let server = tiny_http::Server::http(addr).map_err(|e| MandelError::io(addr, io::Error::other(e)))?;
+        eprintln!("serving http://{}/ (tiles cached in {})", addr, self.cache.display());
+
+        for request in server.incoming_requests() {
+            let start = Instant::now();
+            let url = request.url().to_string();
+            let (response, note) = self.respond(&url);
+            if self.verbose {
+                eprintln!("{} {} {} {:.3}s", url, response.status_code().0, note, start.elapsed().as_secs_f64());
+            }
+            // 応答を書き込めないのはブラウザが要求を取り消した場合なので、無視して次に進む
+            let _ = request.respond(response);
+        }
+        Ok(())
+    }
+
+    /// urlに対する応答と、表示用の説明を返す
+    fn respond(&self, url: &str) -> (tiny_http::Response<io::Cursor<Vec<u8>>>, &'static str) {
+        let content_type = |value: &str| {
+            tiny_http::Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).expect("valid header")
+        };
+        if url == "/" {
+            let page = INDEX_HTML.replace("TILE_SIZE", &self.map.tile_size.to_string())
+                .replace("MAX_ZOOM", &MAX_ZOOM.to_string());
+            return (tiny_http::Response::from_string(page).with_header(content_type("text/html; charset=utf-8")), "");
+        }
+        let tile = parse_tile_path(url).and_then(|(z, x, y)| Some(((z, x, y), self.map.viewport(z, x, y)?)));
+        let ((z, x, y), viewport) = match tile {
+            Some(tile) => tile,
+            None => return (tiny_http::Response::from_string("not found").with_status_code(404), ""),
+        };
+        match self.tile(z, x, y, &viewport) {
+            Ok((bytes, cached)) => {
+                let note = if cached { "cached" } else { "rendered" };
+                (tiny_http::Response::from_data(bytes).with_header(content_type("image/png")), note)
+            }
+            Err(e) => (tiny_http::Response::from_string(e.to_string()).with_status_code(500), "error"),
+        }
+    }
+
+    /// タイルのPNGファイルの中身を返す 保存されていなければ描画して保存する
+    /// 戻り値の2つ目は保存されていたかどうか
+    fn tile(&self, z: u32, x: usize, y: usize, viewport: &Viewport) -> Result<(Vec<u8>, bool), MandelError> {
+        let dir = self.cache.join(z.to_string()).join(x.to_string());
+        let path = dir.join(format!("{}.png", y));
+        if let Ok(bytes) = fs::read(&path) {
+            return Ok((bytes, true));
+        }
+
+        let mut pixels = vec![0; viewport.bounds.0 * viewport.bounds.1 * self.params.format.channels()];
+        render_image(&mut pixels, viewport.bounds, viewport.upper_left, viewport.lower_right, &self.params);
+
+        fs::create_dir_all(&dir).map_err(|e| MandelError::io(&dir, e))?;
+        cache::write_then_rename(&dir.join(format!("{}.tmp.png", y)), &path, |temporary| {
+            write_image(&temporary.to_string_lossy(), &pixels, viewport.bounds, self.params.format)
+        })?;
+        let bytes = fs::read(&path).map_err(|e| MandelError::io(&path, e))?;
+        Ok((bytes, false))
+    }
+
+    /// ディレクトリを用意し、描画の指定を書き込む
+    /// 別の描画のタイルが保存されている場合はエラーとする
+    fn prepare(&self) -> Result<(), MandelError> {
+        match cache::claim(&self.cache, KEY_FILE, &self.key)? {
+            Some(saved) if saved != self.key => Err(MandelError::TileCache {
+                dir: self.cache.display().to_string(),
+                message: format!("holds tiles of a different render (remove it or choose another directory):\n  {}", saved),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_tile_viewport() {
+    let map = TileMap { center: Complex { re: -0.5, im: 0.0 }, width: 4.0, tile_size: 256 };
+    let whole = map.viewport(0, 0, 0).unwrap();
+    assert_eq!(whole.upper_left, Complex { re: -2.5, im: 2.0 });
+    assert_eq!(whole.lower_right, Complex { re: 1.5, im: -2.0 });
+
+    // ズームレベル1の右下のタイルは、全体の右下の1/4
+    let tile = map.viewport(1, 1, 1).unwrap();
+    assert_eq!(tile.bounds, (256, 256));
+    assert_eq!(tile.upper_left, Complex { re: -0.5, im: 0.0 });
+    assert_eq!(tile.lower_right, Complex { re: 1.5, im: -2.0 });
+
+    assert_eq!(map.viewport(1, 2, 0), None);
+    assert_eq!(map.viewport(MAX_ZOOM + 1, 0, 0), None);
+}
+
+#[test]
+fn test_parse_tile_path() {
+    assert_eq!(parse_tile_path("/tiles/3/5/2.png"), Some((3, 5, 2)));
+    assert_eq!(parse_tile_path("/tiles/3/5.png"), None);
+    assert_eq!(parse_tile_path("/tiles/3/5/2/1.png"), None);
+    assert_eq!(parse_tile_path("/tiles/3/-5/2.png"), None);
+    assert_eq!(parse_tile_path("/other/3/5/2.png"), None);
+}
+
+#[test]
+fn test_tile_cache() {
+    let cache = std::env::temp_dir().join(format!("mandelbrot-tiles-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&cache);
+    let server = TileServer {
+        map: TileMap { center: Complex { re: -0.5, im: 0.0 }, width: 4.0, tile_size: 16 },
+        params: RenderParams { threads: 2, ..RenderParams::default() },
+        cache: cache.clone(),
+        key: String::from("test"),
+        verbose: false,
+    };
+    server.prepare().unwrap();
+
+    let (response, note) = server.respond("/tiles/1/0/1.png");
+    assert_eq!((response.status_code().0, note), (200, "rendered"));
+    assert!(cache.join("1").join("0").join("1.png").exists());
+    let (_, note) = server.respond("/tiles/1/0/1.png");
+    assert_eq!(note, "cached");
+    assert_eq!(server.respond("/tiles/1/0/2.png").0.status_code().0, 404);
+
+    // 別の描画の指定では同じディレクトリを使わない
+    let other = TileServer { key: String::from("other"), ..server };
+    assert_eq!(other.prepare().unwrap_err().exit_code(), 6);
+    fs::remove_dir_all(&cache).unwrap();
+}
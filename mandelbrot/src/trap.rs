@@ -0,0 +1,124 @@
+//! オービットトラップ (orbit trap) による色付け
+//!
+//! 繰り返しの間にzが図形 (トラップ) に最も近づいた距離を記録し、その距離で色付けする
+//! 脱出までの回数とは関係なく軌道の形が模様になるので、集合の内部の点も色付けする
+
+use num::Complex;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::formula::Formula;
+
+/// 軌道との距離を測る図形
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    /// 1点
+    Point(Complex<f64>),
+    /// pointを通り、単位ベクトルdirectionの向きの直線
+    Line { point: Complex<f64>, direction: Complex<f64> },
+    /// 点を通り、実軸と虚軸に平行な2本の直線
+    Cross(Complex<f64>),
+    /// 円周
+    Circle { center: Complex<f64>, radius: f64 },
+}
+
+impl Trap {
+    /// zから図形までの距離
+    pub fn distance(&self, z: Complex<f64>) -> f64 {
+        match *self {
+            Trap::Point(point) => (z - point).norm(),
+            // directionを実軸に回した時の虚部が、直線からの距離になる
+            Trap::Line { point, direction } => ((z - point) * direction.conj()).im.abs(),
+            Trap::Cross(point) => (z.re - point.re).abs().min((z.im - point.im).abs()),
+            Trap::Circle { center, radius } => ((z - center).norm() - radius).abs(),
+        }
+    }
+}
+
+/// "point:RE,IM", "line:RE,IM:DEG" (DEGは実軸からの角度), "cross:RE,IM", "circle:RE,IM:R" をパースする
+/// 名前だけなら原点を通る (lineは実軸、circleは半径1) ものとする
+impl FromStr for Trap {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Trap, ()> {
+        let (name, rest) = s.split_once(':').unwrap_or((s, "0,0"));
+        let (point, parameter) = match rest.split_once(':') {
+            Some((point, parameter)) => (point, Some(f64::from_str(parameter).map_err(|_| ())?)),
+            None => (rest, None),
+        };
+        let point = crate::parse_complex(point).ok_or(())?;
+        match (name, parameter) {
+            ("point", None) => Ok(Trap::Point(point)),
+            ("cross", None) => Ok(Trap::Cross(point)),
+            ("line", angle) => {
+                Ok(Trap::Line { point, direction: Complex::from_polar(1.0, angle.unwrap_or(0.0).to_radians()) })
+            }
+            ("circle", radius) => match radius.unwrap_or(1.0) {
+                radius if radius > 0.0 => Ok(Trap::Circle { center: point, radius }),
+                _ => Err(()),
+            },
+            _ => Err(()),
+        }
+    }
+}
+
+/// FromStrと同じ形式で書き出す
+/// 直線の角度は度に戻す時の誤差を丸める
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Point(p) => write!(f, "point:{},{}", p.re, p.im),
+            Trap::Line { point, direction } => {
+                let angle = (direction.arg().to_degrees() * 1e9).round() / 1e9;
+                write!(f, "line:{},{}:{}", point.re, point.im, angle)
+            }
+            Trap::Cross(p) => write!(f, "cross:{},{}", p.re, p.im),
+            Trap::Circle { center, radius } => write!(f, "circle:{},{}:{}", center.re, center.im, radius),
+        }
+    }
+}
+
+/// zを初期値としてformulaの漸化式を繰り返し、軌道z_1, z_2, ...がtrapに最も近づいた距離を返す
+/// escape_time_fromと同じく、半径2の円から出るか繰り返し上限に達したら終わる (出た点も含める)
+pub fn trap_distance(mut z: Complex<f64>, c: Complex<f64>, limit: usize, formula: &Formula, trap: &Trap) -> f64 {
+    let mut nearest = f64::INFINITY;
+    for _ in 0..limit {
+        if z.norm_sqr() > 4.0 {
+            break;
+        }
+        z = formula.step(z, c);
+        nearest = nearest.min(trap.distance(z));
+    }
+    nearest
+}
+
+#[test]
+fn test_parse_trap() {
+    let origin = Complex { re: 0.0, im: 0.0 };
+    assert_eq!("point".parse(), Ok(Trap::Point(origin)));
+    assert_eq!("cross:0.5,-1".parse(), Ok(Trap::Cross(Complex { re: 0.5, im: -1.0 })));
+    assert_eq!("circle:0,0:0.25".parse(), Ok(Trap::Circle { center: origin, radius: 0.25 }));
+    assert!("circle:0,0:-1".parse::<Trap>().is_err());
+    assert!("point:0,0:1".parse::<Trap>().is_err());
+    assert!("square".parse::<Trap>().is_err());
+    assert!("point:1".parse::<Trap>().is_err());
+    for s in ["point:0.5,-1", "line:0,0.5:30", "line:0,0:-45", "cross:0,0", "circle:-1,0:0.5"] {
+        assert_eq!(s.parse::<Trap>().unwrap().to_string(), s);
+    }
+}
+
+#[test]
+fn test_trap_distance() {
+    let line: Trap = "line:0,1:45".parse().unwrap();
+    assert!((line.distance(Complex { re: 1.0, im: 0.0 }) - 2f64.sqrt()).abs() < 1e-12);
+    let circle: Trap = "circle:1,0:2".parse().unwrap();
+    assert_eq!(circle.distance(Complex { re: 1.0, im: 0.5 }), 1.5);
+
+    // c = 1の軌道は1, 2, 5で脱出する
+    let c = Complex { re: 1.0, im: 0.0 };
+    let zero = Complex { re: 0.0, im: 0.0 };
+    assert_eq!(trap_distance(zero, c, 255, &Formula::Mandelbrot, &Trap::Point(Complex { re: 2.0, im: 0.0 })), 0.0);
+    assert_eq!(trap_distance(zero, c, 255, &Formula::Mandelbrot, &"cross:3,3".parse().unwrap()), 1.0);
+    // 初期値z_0は含めない c = 0の軌道は0のまま
+    assert_eq!(trap_distance(zero, zero, 10, &Formula::Mandelbrot, &"circle".parse().unwrap()), 1.0);
+}
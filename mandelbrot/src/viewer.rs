@@ -0,0 +1,350 @@
+//! 端末の中で移動や拡大をしながら眺めるビューア
+//!
+//! 端末の1文字を上下2つのピクセルとみなし、上半分のブロック文字'▀'の文字色を上のピクセル、
+//! 背景色を下のピクセルの色にして24ビットカラーで描く (sixelに対応した端末ではsixelでも描ける)
+//! キーを押すたびに今の範囲をrender_imageで描き直す
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, terminal};
+use num::Complex;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::animation::frame_filename;
+use crate::error::MandelError;
+use crate::output::render_text;
+use crate::palette::PixelFormat;
+use crate::{pixel_to_point, render_image, write_image_with_text, RenderParams, Viewport};
+
+/// ]キーで繰り返し上限を倍にしていく時の最大値
+/// 描き直しが終わるまでキー入力に応じられないので、これより大きくはしない
+const MAX_LIMIT: usize = 1 << 24;
+
+/// 端末への描き方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Graphics {
+    /// 上半分のブロック文字と24ビットカラーのエスケープシーケンス
+    HalfBlock,
+    /// sixel (6ピクセルの縦の並びを1文字で表す画像の形式)
+    Sixel,
+}
+
+/// 表示している範囲
+/// 画面の大きさは端末の大きさで変わるので、中心と実軸方向の幅で持つ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub center: Complex<f64>,
+    pub width: f64,
+}
+
+impl View {
+    /// 大きさboundsの画像に描く範囲
+    pub fn viewport(&self, bounds: (usize, usize)) -> Viewport {
+        Viewport::centered(bounds, self.center, self.width)
+    }
+
+    /// 大きさboundsの画面の1/8を単位として、中心を右にstep.0、下にstep.1だけ動かす
+    /// 画面を回転している時は、画面の上での向きに動かす
+    pub fn pan(&mut self, bounds: (usize, usize), step: (isize, isize), rotation: f64) {
+        let viewport = self.viewport(bounds);
+        let pixel = |size: usize, step: isize| (size / 2).saturating_add_signed(step * (size / 8) as isize);
+        let point = pixel_to_point(bounds, (pixel(bounds.0, step.0), pixel(bounds.1, step.1)),
+                                   viewport.upper_left, viewport.lower_right);
+        self.center += (point - self.center) * Complex::from_polar(1.0, rotation);
+    }
+
+    /// factor倍に拡大する (1より小さければ縮小する)
+    pub fn zoom(&mut self, factor: f64) {
+        self.width /= factor;
+    }
+}
+
+/// ビューアの状態
+#[derive(Debug, Clone)]
+pub struct Viewer {
+    pub view: View,
+    /// 描画方法 端末にはformatによらずRGBで描く
+    pub params: RenderParams,
+    pub graphics: Graphics,
+    /// sキーで保存する画像のファイル名 保存するたびにframe_filenameで連番を付ける
+    pub save_filename: String,
+    /// 保存する画像の大きさ 範囲は画面と同じ中心と幅で、高さはこの縦横比に合わせる
+    pub save_bounds: (usize, usize),
+}
+
+/// 端末の入出力のエラー
+fn terminal_error(error: io::Error) -> MandelError {
+    MandelError::io("terminal", error)
+}
+
+/// 端末を元の状態に戻す
+/// エラーで抜けた場合も戻るよう、Dropで行う
+struct RawMode;
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+impl Viewer {
+    /// 端末を全画面で使って表示し、キー入力に応じて描き直す qかEscで終わる
+    ///
+    /// 矢印キー (hjklも可) で画面の1/8ずつ移動、+と-で2倍ずつ拡大縮小、
+    /// ]と[で繰り返し回数の上限を2倍、1/2にし、sで今の範囲をsave_boundsの大きさのファイルに保存する
+    pub fn run(&mut self) -> Result<(), MandelError> {
+        let mut stdout = io::stdout();
+        terminal::enable_raw_mode().map_err(terminal_error)?;
+        let _raw_mode = RawMode;
+        crossterm::execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide).map_err(terminal_error)?;
+
+        let mut message = String::from("arrows: move  +/-: zoom  [/]: max-iter  s: save  q: quit");
+        let mut saved = 0;
+        loop {
+            let bounds = self.draw(&mut stdout, &message)?;
+            let code = match event::read().map_err(terminal_error)? {
+                Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. }) => {
+                    // rawモードではCtrl-Cもキー入力として届く
+                    if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+                        return Ok(());
+                    }
+                    code
+                }
+                // 端末の大きさが変わった場合なども、描き直す
+                _ => continue,
+            };
+            let rotation = self.params.rotation;
+            match code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Left | KeyCode::Char('h') => self.view.pan(bounds, (-1, 0), rotation),
+                KeyCode::Right | KeyCode::Char('l') => self.view.pan(bounds, (1, 0), rotation),
+                KeyCode::Up | KeyCode::Char('k') => self.view.pan(bounds, (0, -1), rotation),
+                KeyCode::Down | KeyCode::Char('j') => self.view.pan(bounds, (0, 1), rotation),
+                KeyCode::Char('+') | KeyCode::Char('=') => self.view.zoom(2.0),
+                KeyCode::Char('-') => self.view.zoom(0.5),
+                KeyCode::Char(']') => self.params.limit = double_limit(self.params.limit),
+                KeyCode::Char('[') => self.params.limit = (self.params.limit / 2).max(1),
+                KeyCode::Char('s') => {
+                    // 既にあるファイルは上書きしない
+                    while Path::new(&frame_filename(&self.save_filename, saved)).exists() {
+                        saved += 1;
+                    }
+                    let filename = frame_filename(&self.save_filename, saved);
+                    self.save(&filename)?;
+                    message = format!("saved {}", filename);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// 今の範囲を画面に描き、ステータス行にmessageを表示する
+    /// 戻り値は画面に描いた画像の大きさ (ピクセル数)
+    fn draw(&self, stdout: &mut io::Stdout, message: &str) -> Result<(usize, usize), MandelError> {
+        let (columns, rows) = terminal::size().map_err(terminal_error)?;
+        let (columns, rows) = (columns as usize, (rows as usize).saturating_sub(1).max(1));
+        let bounds = match self.graphics {
+            Graphics::HalfBlock => (columns, rows * 2),
+            Graphics::Sixel => {
+                // 端末がピクセル数を教えてくれなければ、1文字を10x20ピクセルとみなす
+                let (cell_width, cell_height) = match terminal::window_size() {
+                    Ok(size) if size.width > 0 && size.height > 0 => {
+                        (size.width as usize / columns, size.height as usize / (rows + 1))
+                    }
+                    _ => (10, 20),
+                };
+                (columns * cell_width, (rows * cell_height / 6 * 6).max(6))
+            }
+        };
+
+        let start = Instant::now();
+        let params = RenderParams { format: PixelFormat::Rgb8, ..self.params.clone() };
+        let viewport = self.view.viewport(bounds);
+        let mut pixels = vec![0; bounds.0 * bounds.1 * 3];
+        render_image(&mut pixels, bounds, viewport.upper_left, viewport.lower_right, &params);
+        let elapsed = start.elapsed();
+
+        let result = (|| {
+            match self.graphics {
+                Graphics::HalfBlock => {
+                    for (y, line) in half_block_lines(&pixels, bounds).iter().enumerate() {
+                        queue!(stdout, cursor::MoveTo(0, y as u16))?;
+                        stdout.write_all(line.as_bytes())?;
+                    }
+                }
+                Graphics::Sixel => {
+                    queue!(stdout, cursor::MoveTo(0, 0))?;
+                    stdout.write_all(sixel_image(&pixels, bounds).as_bytes())?;
+                }
+            }
+            let status = format!("{},{}  width {:e}  max-iter {}  {:.0}ms  {}",
+                                 self.view.center.re, self.view.center.im, self.view.width,
+                                 self.params.limit, elapsed.as_secs_f64() * 1000.0, message);
+            queue!(stdout, cursor::MoveTo(0, rows as u16), terminal::Clear(terminal::ClearType::CurrentLine))?;
+            stdout.write_all(status.chars().take(columns).collect::<String>().as_bytes())?;
+            stdout.flush()
+        })();
+        result.map_err(terminal_error)?;
+        Ok(bounds)
+    }
+
+    /// 今の範囲をsave_boundsの大きさで描画し、params.formatでfilenameに書き出す
+    /// PNGには描画の指定を記録するので、--fromで同じ範囲を描き直せる
+    fn save(&self, filename: &str) -> Result<(), MandelError> {
+        let viewport = self.view.viewport(self.save_bounds);
+        let mut pixels = vec![0; self.save_bounds.0 * self.save_bounds.1 * self.params.format.channels()];
+        render_image(&mut pixels, self.save_bounds, viewport.upper_left, viewport.lower_right, &self.params);
+        let corner = |p: Complex<f64>| format!("{},{}", p.re, p.im);
+        let text = render_text(&self.params, self.save_bounds, corner(viewport.upper_left), corner(viewport.lower_right));
+        write_image_with_text(filename, &pixels, self.save_bounds, self.params.format, &text)
+    }
+}
+
+/// RGBのピクセルを、2行ずつ上半分のブロック文字の行にする
+/// 高さが奇数なら最後の行の下半分は端末の背景色のままにする
+/// 前の文字と同じ色ならエスケープシーケンスを省く
+pub fn half_block_lines(pixels: &[u8], bounds: (usize, usize)) -> Vec<String> {
+    let row_len = bounds.0 * 3;
+    pixels.chunks(row_len * 2).map(|rows| {
+        let (top, bottom) = rows.split_at(row_len);
+        let mut line = String::new();
+        let mut previous = None;
+        for x in 0..bounds.0 {
+            let colors = (&top[x * 3..x * 3 + 3], bottom.get(x * 3..x * 3 + 3));
+            if previous != Some(colors) {
+                let (t, b) = colors;
+                write!(line, "\x1b[38;2;{};{};{}m", t[0], t[1], t[2]).unwrap();
+                match b {
+                    Some(b) => write!(line, "\x1b[48;2;{};{};{}m", b[0], b[1], b[2]).unwrap(),
+                    None => line.push_str("\x1b[49m"),
+                }
+                previous = Some(colors);
+            }
+            line.push('▀');
+        }
+        line.push_str("\x1b[0m");
+        line
+    }).collect()
+}
+
+/// RGBのピクセルをsixelの画像にする
+/// 色は各成分を6段階にした216色に減らす
+pub fn sixel_image(pixels: &[u8], bounds: (usize, usize)) -> String {
+    let (width, height) = bounds;
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for i in 0..216 {
+        write!(out, "#{};2;{};{};{}", i, i / 36 * 20, i / 6 % 6 * 20, i % 6 * 20).unwrap();
+    }
+    let level = |v: u8| (v as usize * 5 + 127) / 255;
+    let color = |x: usize, y: usize| {
+        let p = &pixels[(y * width + x) * 3..];
+        level(p[0]) * 36 + level(p[1]) * 6 + level(p[2])
+    };
+
+    for top in (0..height).step_by(6) {
+        // 6行の帯の中で、色ごとに各列のどの行がその色かをビットで表す
+        let mut bands: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        for dy in 0..6.min(height - top) {
+            for x in 0..width {
+                bands.entry(color(x, top + dy)).or_insert_with(|| vec![0; width])[x] |= 1 << dy;
+            }
+        }
+        for (i, (color, bits)) in bands.iter().enumerate() {
+            // $で帯の先頭に戻って次の色を重ねる
+            if i > 0 {
+                out.push('$');
+            }
+            write!(out, "#{}", color).unwrap();
+            let mut x = 0;
+            while x < width {
+                let run = bits[x..].iter().take_while(|&&b| b == bits[x]).count();
+                let c = (63 + bits[x]) as char;
+                if run > 3 {
+                    write!(out, "!{}{}", run, c).unwrap();
+                } else {
+                    out.extend(std::iter::repeat_n(c, run));
+                }
+                x += run;
+            }
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+/// 繰り返し上限を倍にする MAX_LIMIT (コマンドラインでそれより大きく指定していればその値) で止める
+fn double_limit(limit: usize) -> usize {
+    limit.saturating_mul(2).min(MAX_LIMIT).max(limit)
+}
+
+#[test]
+fn test_double_limit() {
+    assert_eq!(double_limit(255), 510);
+    assert_eq!(double_limit(MAX_LIMIT - 1), MAX_LIMIT);
+    assert_eq!(double_limit(MAX_LIMIT), MAX_LIMIT);
+    assert_eq!(double_limit(usize::MAX), usize::MAX);
+}
+
+#[test]
+fn test_view_pan_and_zoom() {
+    let bounds = (80, 40);
+    let mut view = View { center: Complex { re: -0.5, im: 0.0 }, width: 4.0 };
+    // 画面の幅80ピクセルの1/8は10ピクセルで、0.5にあたる
+    view.pan(bounds, (1, 0), 0.0);
+    assert!((view.center - Complex { re: 0.0, im: 0.0 }).norm() < 1e-12);
+    view.pan(bounds, (0, -1), 0.0);
+    assert!((view.center - Complex { re: 0.0, im: 0.25 }).norm() < 1e-12);
+
+    // 90度回した画面で右に動くと、複素平面では上に動く
+    view.pan(bounds, (1, 0), std::f64::consts::FRAC_PI_2);
+    assert!((view.center - Complex { re: 0.0, im: 0.75 }).norm() < 1e-12);
+
+    view.zoom(2.0);
+    assert_eq!(view.width, 2.0);
+    assert_eq!(view.viewport(bounds).upper_left, Complex { re: -1.0, im: 1.25 });
+}
+
+#[test]
+fn test_half_block_and_sixel() {
+    let bounds = (2, 3);
+    let pixels = [255, 0, 0, 255, 0, 0,
+                  0, 0, 255, 0, 0, 255,
+                  255, 255, 255, 0, 0, 0];
+    let lines = half_block_lines(&pixels, bounds);
+    assert_eq!(lines, vec![
+        "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m▀▀\x1b[0m".to_string(),
+        "\x1b[38;2;255;255;255m\x1b[49m▀\x1b[38;2;0;0;0m\x1b[49m▀\x1b[0m".to_string(),
+    ]);
+
+    // 赤 (180番) は1行目、青 (5番) は2行目、白 (215番) と黒 (0番) は3行目
+    let sixel = sixel_image(&pixels, bounds);
+    assert!(sixel.starts_with("\x1bPq\"1;1;2;3#0;2;0;0;0"));
+    assert!(sixel.ends_with("#0?C$#5AA$#180@@$#215C?-\x1b\\"));
+}
+
+#[test]
+fn test_save_records_view() {
+    let filename = std::env::temp_dir().join(format!("mandelbrot-viewer-test-{}.png", std::process::id()));
+    let filename = filename.to_string_lossy().to_string();
+    let viewer = Viewer {
+        view: View { center: Complex { re: -0.75, im: 0.1 }, width: 0.5 },
+        params: RenderParams { limit: 300, ..RenderParams::default() },
+        graphics: Graphics::HalfBlock,
+        save_filename: filename.clone(),
+        save_bounds: (40, 30),
+    };
+    viewer.save(&filename).unwrap();
+    let text = crate::output::read_text(&filename).unwrap();
+    std::fs::remove_file(&filename).unwrap();
+
+    let viewport = viewer.view.viewport(viewer.save_bounds);
+    let recorded = |key: &str| text.iter().find(|(k, _)| *k == format!("mandelbrot:{}", key)).map(|(_, v)| v.as_str());
+    assert_eq!(recorded("pixels"), Some("40x30"));
+    assert_eq!(recorded("upper-left"), Some(format!("{},{}", viewport.upper_left.re, viewport.upper_left.im).as_str()));
+    assert_eq!(recorded("max-iter"), Some("300"));
+}